@@ -0,0 +1,56 @@
+// Measures the dispatch loop on long straight-line programs. The language has
+// no functions or loops yet, so each program repeats one expression many
+// times to keep the VM busy decoding and running instructions. Chunks are
+// verified once up front; straight-line code runs each instruction once, so
+// verifying on every iteration would weigh as much as the dispatch itself.
+// Folding is turned off so each operator still runs, without allocating
+// anything on the VM heap.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rox::{compile, verify, Chunk, DebugOptions, RVM};
+
+const REPEAT: usize = 1000;
+
+fn program(expr: &str, op: &str) -> String {
+    vec![format!("({})", expr); REPEAT].join(op)
+}
+
+fn dispatch_benches(c: &mut Criterion) {
+    let programs = [
+        ("arithmetic", program("1 + 2 * 3 - 4 // 2 + 5 % 3", " + ")),
+        ("floats", program("1.5 * 2.0 - 0.25 / 4.0", " + ")),
+        ("comparison", program("1 < 2 == !(3 >= 4)", " == ")),
+        ("bitwise", program("(12 & 10) | (1 << 4) ^ ~7", " + ")),
+    ];
+
+    let mut group = c.benchmark_group("dispatch");
+    for (name, source) in programs {
+        let mut vm = RVM::new();
+        vm.set_debug_options(DebugOptions {
+            no_fold: true,
+            ..DebugOptions::default()
+        });
+        let mut chunk = Chunk::new();
+        assert!(compile(&mut vm, &source, &mut chunk));
+        let Ok(usage) = verify(&chunk) else {
+            panic!("{} did not verify", name);
+        };
+
+        // Objects are never freed, so a program that allocates would pile up
+        // garbage across iterations and end up measuring the allocator
+        unsafe { vm.run_verified(&chunk, &usage).ok() };
+        assert!(
+            vm.heap_stats().iter().all(|&(_, count)| count == 0),
+            "{} allocates on the VM heap",
+            name
+        );
+
+        group.bench_function(name, |b| {
+            b.iter(|| unsafe { black_box(vm.run_verified(black_box(&chunk), &usage).ok()) })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dispatch_benches);
+criterion_main!(benches);
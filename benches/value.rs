@@ -0,0 +1,76 @@
+// Compares the two `Value` representations. Run once per representation and
+// criterion reports both under the same group:
+//
+//   cargo bench --bench value
+//   cargo bench --bench value --features nan-boxing
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rox::{Unpacked, Value, RVM};
+
+const REPR: &str = if cfg!(feature = "nan-boxing") {
+    "nan-boxed"
+} else {
+    "enum"
+};
+
+fn mixed_values(vm: &mut RVM, n: usize) -> Vec<Value> {
+    (0..n)
+        .map(|i| match i % 4 {
+            0 => vm.int(i as i64),
+            1 => Value::number(i as f64 * 0.5),
+            2 => Value::boolean(i % 8 == 2),
+            _ => Value::nil(),
+        })
+        .collect()
+}
+
+fn value_benches(c: &mut Criterion) {
+    let mut vm = RVM::new();
+    let values = mixed_values(&mut vm, 4096);
+    let mut group = c.benchmark_group("value");
+
+    group.bench_function(format!("sum/{REPR}"), |b| {
+        b.iter(|| {
+            black_box(&values)
+                .iter()
+                .filter_map(Value::as_f64)
+                .sum::<f64>()
+        })
+    });
+
+    group.bench_function(format!("truthiness/{REPR}"), |b| {
+        b.iter(|| black_box(&values).iter().filter(|v| v.is_falsey()).count())
+    });
+
+    group.bench_function(format!("equality/{REPR}"), |b| {
+        b.iter(|| {
+            let values = black_box(&values);
+            values.windows(2).filter(|w| w[0] == w[1]).count()
+        })
+    });
+
+    group.bench_function(format!("copy/{REPR}"), |b| {
+        let mut stack = vec![Value::nil(); values.len()];
+        b.iter(|| {
+            stack.clone_from_slice(black_box(&values));
+            black_box(&stack);
+        })
+    });
+
+    group.bench_function(format!("int_arithmetic/{REPR}"), |b| {
+        b.iter(|| {
+            let mut acc = vm.int(0);
+            for v in black_box(&values) {
+                if let (Unpacked::Int(a), Unpacked::Int(n)) = (acc.unpack(), v.unpack()) {
+                    acc = vm.int(a.wrapping_add(n));
+                }
+            }
+            acc
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, value_benches);
+criterion_main!(benches);
@@ -0,0 +1,199 @@
+use crate::scanner::TokenKind;
+use std::fmt;
+
+/// Byte range of a node in the source, with the lines of its first and last
+/// tokens. Code for a node is emitted at `end_line`, the line the single-pass
+/// compiler has reached when it emits the same instruction.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub end_line: usize,
+}
+
+impl Span {
+    /// The span from the start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+            line: self.line,
+            end_line: other.end_line,
+        }
+    }
+}
+
+/// A parsed source file. The language has no statements yet, so a script is
+/// a single expression.
+pub struct Script<'a> {
+    pub body: Expr<'a>,
+    /// Span of the end of input, where the final `Return` is emitted
+    pub eof: Span,
+}
+
+pub struct Expr<'a> {
+    pub kind: ExprKind<'a>,
+    pub span: Span,
+}
+
+pub enum ExprKind<'a> {
+    Literal(Literal<'a>),
+    Grouping(Box<Expr<'a>>),
+    Unary {
+        op: TokenKind,
+        operand: Box<Expr<'a>>,
+    },
+    Binary {
+        op: TokenKind,
+        lhs: Box<Expr<'a>>,
+        rhs: Box<Expr<'a>>,
+    },
+    List(Vec<Expr<'a>>),
+    Map(Vec<(Expr<'a>, Expr<'a>)>),
+    Index {
+        target: Box<Expr<'a>>,
+        index: Box<Expr<'a>>,
+    },
+    IndexSet {
+        target: Box<Expr<'a>>,
+        index: Box<Expr<'a>>,
+        value: Box<Expr<'a>>,
+    },
+    Invoke {
+        receiver: Box<Expr<'a>>,
+        name: Ident<'a>,
+        args: Vec<Expr<'a>>,
+    },
+    /// Stands in for an expression that failed to parse, so parsing can go on
+    /// and report later errors too
+    Error,
+}
+
+pub enum Literal<'a> {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Number(f64),
+    /// Contents of a string literal, without the quotes
+    String(&'a str),
+}
+
+pub struct Ident<'a> {
+    pub name: &'a str,
+    pub span: Span,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}, {}..{}]", self.line, self.start, self.end)
+    }
+}
+
+impl fmt::Display for Literal<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Literal::Nil => write!(f, "nil"),
+            Literal::Bool(b) => write!(f, "{}", b),
+            Literal::Int(n) => write!(f, "{}", n),
+            Literal::Number(n) => write!(f, "{:?}", n),
+            Literal::String(s) => write!(f, "\"{}\"", s),
+        }
+    }
+}
+
+/// Prints the tree one node per line, children indented under their parent.
+impl fmt::Display for Expr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_tree(f, 0)
+    }
+}
+
+impl Expr<'_> {
+    fn fmt_tree(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "{:indent$}", "", indent = depth * 2)?;
+        let children: Vec<&Expr> = match &self.kind {
+            ExprKind::Literal(literal) => {
+                writeln!(f, "Literal {} {}", literal, self.span)?;
+                vec![]
+            }
+            ExprKind::Grouping(inner) => {
+                writeln!(f, "Grouping {}", self.span)?;
+                vec![inner]
+            }
+            ExprKind::Unary { op, operand } => {
+                writeln!(f, "Unary {} {}", symbol(*op), self.span)?;
+                vec![operand]
+            }
+            ExprKind::Binary { op, lhs, rhs } => {
+                writeln!(f, "Binary {} {}", symbol(*op), self.span)?;
+                vec![lhs, rhs]
+            }
+            ExprKind::List(items) => {
+                writeln!(f, "List {}", self.span)?;
+                items.iter().collect()
+            }
+            ExprKind::Map(entries) => {
+                writeln!(f, "Map {}", self.span)?;
+                entries.iter().flat_map(|(k, v)| [k, v]).collect()
+            }
+            ExprKind::Index { target, index } => {
+                writeln!(f, "Index {}", self.span)?;
+                vec![target, index]
+            }
+            ExprKind::IndexSet {
+                target,
+                index,
+                value,
+            } => {
+                writeln!(f, "IndexSet {}", self.span)?;
+                vec![target, index, value]
+            }
+            ExprKind::Invoke {
+                receiver,
+                name,
+                args,
+            } => {
+                writeln!(f, "Invoke {} {}", name.name, self.span)?;
+                std::iter::once(&**receiver).chain(args).collect()
+            }
+            ExprKind::Error => {
+                writeln!(f, "Error {}", self.span)?;
+                vec![]
+            }
+        };
+
+        for child in children {
+            child.fmt_tree(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+fn symbol(op: TokenKind) -> &'static str {
+    match op {
+        TokenKind::Minus => "-",
+        TokenKind::Plus => "+",
+        TokenKind::Slash => "/",
+        TokenKind::SlashSlash => "//",
+        TokenKind::Star => "*",
+        TokenKind::StarStar => "**",
+        TokenKind::Percent => "%",
+        TokenKind::Ampersand => "&",
+        TokenKind::Pipe => "|",
+        TokenKind::Caret => "^",
+        TokenKind::Tilde => "~",
+        TokenKind::Bang => "!",
+        TokenKind::BangEqual => "!=",
+        TokenKind::EqualEqual => "==",
+        TokenKind::Greater => ">",
+        TokenKind::GreaterEqual => ">=",
+        TokenKind::GreaterGreater => ">>",
+        TokenKind::Less => "<",
+        TokenKind::LessEqual => "<=",
+        TokenKind::LessLess => "<<",
+        TokenKind::DotDot => "..",
+        TokenKind::DotDotEqual => "..=",
+        _ => unreachable!(),
+    }
+}
@@ -0,0 +1,301 @@
+use crate::chunk::{Chunk, LineStart};
+use crate::object::{ObjKind, ObjString};
+use crate::rvm::RVM;
+use crate::value::{Unpacked, Value};
+use crate::verifier::verify;
+
+// File layout, all integers little-endian:
+//
+//   magic        b"ROXC"
+//   version      u16
+//   constants    u32 count, then per constant a u8 tag and its payload
+//   code         u32 length, then the bytes
+//   lines        u32 run count, then (u32 line, u32 length) runs
+
+pub const MAGIC: &[u8; 4] = b"ROXC";
+pub const VERSION: u16 = 2;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_INT: u8 = 3;
+const TAG_NUMBER: u8 = 4;
+const TAG_STRING: u8 = 5;
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn write_chunk(chunk: &Chunk) -> Result<Vec<u8>, String> {
+    let mut out = vec![];
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+
+    write_u32(&mut out, chunk.constants.values.len())?;
+    for value in &chunk.constants.values {
+        match value.unpack() {
+            Unpacked::Nil => out.push(TAG_NIL),
+            Unpacked::Boolean(false) => out.push(TAG_FALSE),
+            Unpacked::Boolean(true) => out.push(TAG_TRUE),
+            Unpacked::Int(n) => {
+                out.push(TAG_INT);
+                out.extend_from_slice(&n.to_le_bytes());
+            }
+            Unpacked::Number(n) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&n.to_bits().to_le_bytes());
+            }
+            Unpacked::Obj(obj) => unsafe {
+                match (*obj).kind {
+                    ObjKind::String => {
+                        let s = &(*(obj as *mut ObjString)).value;
+                        out.push(TAG_STRING);
+                        write_u32(&mut out, s.len())?;
+                        out.extend_from_slice(s.as_bytes());
+                    }
+                    _ => return Err(format!("Can't serialize constant '{}'", value)),
+                }
+            },
+        }
+    }
+
+    write_u32(&mut out, chunk.code.len())?;
+    out.extend_from_slice(&chunk.code);
+
+    write_u32(&mut out, chunk.lines.len())?;
+    for (i, start) in chunk.lines.iter().enumerate() {
+        let end = chunk
+            .lines
+            .get(i + 1)
+            .map_or(chunk.code.len(), |l| l.offset);
+        write_u32(&mut out, start.line)?;
+        write_u32(&mut out, end - start.offset)?;
+    }
+
+    Ok(out)
+}
+
+/// Loads a chunk written by `write_chunk`. The file structure is checked here
+/// and the code is run through the verifier, so a truncated or corrupt file
+/// is rejected before it reaches `RVM::run`.
+pub fn read_chunk(vm: &mut RVM, bytes: &[u8]) -> Result<Chunk, String> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("Not a rox bytecode file".to_string());
+    }
+    let version = u16::from_le_bytes(reader.array()?);
+    if version != VERSION {
+        return Err(format!(
+            "Unsupported bytecode version {} (expected {})",
+            version, VERSION
+        ));
+    }
+
+    let mut chunk = Chunk::new();
+
+    let constant_count = reader.u32()?;
+    for _ in 0..constant_count {
+        let value = match reader.u8()? {
+            TAG_NIL => Value::nil(),
+            TAG_FALSE => Value::boolean(false),
+            TAG_TRUE => Value::boolean(true),
+            TAG_INT => vm.int(i64::from_le_bytes(reader.array()?)),
+            TAG_NUMBER => Value::number(f64::from_bits(u64::from_le_bytes(reader.array()?))),
+            TAG_STRING => {
+                let len = reader.u32()?;
+                let s = std::str::from_utf8(reader.take(len)?)
+                    .map_err(|_| "String constant is not valid UTF-8".to_string())?;
+                unsafe { Value::obj(vm.allocate_obj(ObjString::new(s.to_string()))) }
+            }
+            tag => return Err(format!("Unknown constant tag {}", tag)),
+        };
+        chunk.constants.write(value);
+    }
+
+    let code_len = reader.u32()?;
+    chunk.code = reader.take(code_len)?.to_vec();
+
+    let run_count = reader.u32()?;
+    let mut offset = 0;
+    for _ in 0..run_count {
+        let line = reader.u32()?;
+        let count = reader.u32()?;
+        if count == 0 {
+            return Err("Empty run in the line table".to_string());
+        }
+        chunk.lines.push(LineStart { offset, line });
+        offset += count;
+    }
+    if offset != chunk.code.len() {
+        return Err("Line table does not match the code length".to_string());
+    }
+
+    if reader.pos != bytes.len() {
+        return Err("Trailing bytes after the line table".to_string());
+    }
+
+    verify(&chunk)?;
+    Ok(chunk)
+}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) -> Result<(), String> {
+    let n = u32::try_from(n).map_err(|_| format!("{} is too large for the bytecode format", n))?;
+    out.extend_from_slice(&n.to_le_bytes());
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| "Unexpected end of bytecode file".to_string())?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::OpCode;
+    use crate::compiler::compile;
+
+    fn read(bytes: &[u8]) -> Result<(), String> {
+        read_chunk(&mut RVM::new(), bytes).map(drop)
+    }
+
+    fn compiled(source: &str) -> Vec<u8> {
+        let mut chunk = Chunk::new();
+        assert!(compile(&mut RVM::new(), source, &mut chunk));
+        write_chunk(&chunk).unwrap()
+    }
+
+    /// Magic, version and a single constant, with no code or lines yet.
+    fn with_constant(tag: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        write_u32(&mut bytes, 1).unwrap();
+        bytes.push(tag);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let source = "[nil, true, false, 2.5, 9223372036854775807,\n\"s\"][[1][0]] + \"t\"";
+        let mut vm = RVM::new();
+        let mut chunk = Chunk::new();
+        assert!(compile(&mut vm, source, &mut chunk));
+
+        let bytes = write_chunk(&chunk).unwrap();
+        assert!(is_bytecode(&bytes));
+        let read = read_chunk(&mut vm, &bytes).unwrap();
+
+        assert_eq!(read.code, chunk.code);
+        for offset in 0..chunk.code.len() {
+            assert_eq!(read.get_line(offset), chunk.get_line(offset));
+        }
+        let constants = |chunk: &Chunk| {
+            chunk
+                .constants
+                .values
+                .iter()
+                .map(|v| format!("{} {}", v.type_name(), v))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(constants(&read), constants(&chunk));
+        assert_eq!(write_chunk(&read).unwrap(), bytes);
+    }
+
+    #[test]
+    fn rejects_a_bad_header() {
+        let mut bytes = compiled("1");
+        bytes[0] = b'X';
+        assert_eq!(read(&bytes).unwrap_err(), "Not a rox bytecode file");
+
+        let mut bytes = compiled("1");
+        bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            read(&bytes).unwrap_err(),
+            format!(
+                "Unsupported bytecode version {} (expected {})",
+                VERSION + 1,
+                VERSION
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_every_truncation() {
+        let bytes = compiled("\"s\" + [1.5][0]");
+        for len in 0..bytes.len() {
+            assert_eq!(
+                read(&bytes[..len]).unwrap_err(),
+                "Unexpected end of bytecode file",
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_constants() {
+        assert_eq!(
+            read(&with_constant(9, &[])).unwrap_err(),
+            "Unknown constant tag 9"
+        );
+
+        let mut string = vec![];
+        write_u32(&mut string, 2).unwrap();
+        string.extend_from_slice(&[0xc3, 0x28]);
+        assert_eq!(
+            read(&with_constant(TAG_STRING, &string)).unwrap_err(),
+            "String constant is not valid UTF-8"
+        );
+    }
+
+    #[test]
+    fn rejects_a_line_table_that_does_not_cover_the_code() {
+        let mut bytes = with_constant(TAG_NIL, &[]);
+        write_u32(&mut bytes, 2).unwrap();
+        bytes.extend_from_slice(&[OpCode::Constant as u8, 0]);
+        write_u32(&mut bytes, 1).unwrap();
+        write_u32(&mut bytes, 1).unwrap();
+        write_u32(&mut bytes, 3).unwrap();
+        assert_eq!(
+            read(&bytes).unwrap_err(),
+            "Line table does not match the code length"
+        );
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut bytes = compiled("1");
+        assert!(read(&bytes).is_ok());
+        bytes.push(0);
+        assert_eq!(
+            read(&bytes).unwrap_err(),
+            "Trailing bytes after the line table"
+        );
+    }
+}
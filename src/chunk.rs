@@ -1,6 +1,9 @@
-use crate::value::{Value, ValueArray};
+use crate::object::{ObjKind, ObjString};
+use crate::value::{Unpacked, Value, ValueArray};
+use hashbrown::HashMap;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
+use std::io::{self, Write};
 
 #[derive(Copy, Clone, Debug, FromPrimitive)]
 #[repr(u8)]
@@ -11,21 +14,132 @@ pub enum OpCode {
     True,
     False,
     Equal,
+    NotEqual,
     Greater,
+    GreaterEqual,
     Less,
+    LessEqual,
     Add,
+    AddConstant,
     Subtract,
     Multiply,
     Divide,
+    IntDivide,
+    Modulo,
+    Power,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
     Not,
     Negate,
+    BitNot,
+    Range,
+    RangeInclusive,
+    BuildList,
+    BuildMap,
+    IndexGet,
+    IndexSet,
+    Invoke,
     Return,
 }
 
+impl OpCode {
+    /// Number of operand bytes that follow the opcode in the instruction stream.
+    pub fn operand_bytes(self) -> usize {
+        match self {
+            OpCode::Constant | OpCode::AddConstant | OpCode::BuildList | OpCode::BuildMap => 1,
+            OpCode::Invoke => 2,
+            OpCode::ConstantLong => 3,
+            _ => 0,
+        }
+    }
+
+    /// Decodes an opcode byte without checking it. Only for code that has
+    /// been through the verifier.
+    #[inline(always)]
+    pub unsafe fn from_u8_unchecked(byte: u8) -> Self {
+        debug_assert!(OpCode::from_u8(byte).is_some());
+        std::mem::transmute::<u8, OpCode>(byte)
+    }
+}
+
+/// One decoded instruction of a chunk.
+#[derive(Copy, Clone, Debug)]
+pub struct Instruction<'a> {
+    pub offset: usize,
+    pub line: usize,
+    /// `None` if the byte at `offset` isn't an opcode, which only unverified
+    /// bytecode can contain
+    pub opcode: Option<OpCode>,
+    /// Bytes following the opcode, cut short if the code ends first
+    pub operands: &'a [u8],
+}
+
+impl Instruction<'_> {
+    pub fn next_offset(&self) -> usize {
+        self.offset + 1 + self.operands.len()
+    }
+
+    /// Index of the constant this instruction refers to, if it has one.
+    pub fn constant(&self) -> Option<usize> {
+        match (self.opcode?, self.operands) {
+            (OpCode::Constant | OpCode::AddConstant | OpCode::Invoke, &[idx, ..]) => {
+                Some(idx as usize)
+            }
+            (OpCode::ConstantLong, &[a, b, c]) => {
+                Some(((a as usize) << 16) | ((b as usize) << 8) | c as usize)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Iterator over the instructions of a chunk, from `Chunk::instructions`.
+pub struct Instructions<'a> {
+    chunk: &'a Chunk,
+    offset: usize,
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Instruction<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let instruction = self.chunk.instruction_at(self.offset)?;
+        self.offset = instruction.next_offset();
+        Some(instruction)
+    }
+}
+
+/// Start of a run of bytecode that all came from the same source line.
+#[derive(Copy, Clone, Debug)]
+pub struct LineStart {
+    pub offset: usize,
+    pub line: usize,
+}
+
+/// Identity of a constant for deduplication. Floats are compared by bit
+/// pattern, so every NaN with the same payload is shared and -0.0 stays
+/// distinct from 0.0.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Int(i64),
+    Number(u64),
+    String(String),
+}
+
 pub struct Chunk {
     pub code: Vec<u8>,
-    pub lines: Vec<usize>,
+    pub lines: Vec<LineStart>,
     pub constants: ValueArray,
+    constant_indices: HashMap<ConstantKey, usize>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
@@ -34,6 +148,7 @@ impl Chunk {
             code: vec![],
             lines: vec![],
             constants: ValueArray::new(),
+            constant_indices: HashMap::new(),
         }
     }
 
@@ -41,15 +156,42 @@ impl Chunk {
         self.code = vec![]; // We want to actually drop the old memory
         self.lines = vec![]; // We want to actually drop the old memory
         self.constants.free();
+        self.constant_indices = HashMap::new();
     }
 
     pub fn write(&mut self, byte: u8, line: usize) {
+        if self.lines.last().is_none_or(|l| l.line != line) {
+            self.lines.push(LineStart {
+                offset: self.code.len(),
+                line,
+            });
+        }
         self.code.push(byte);
-        self.lines.push(line);
+    }
+
+    /// Drops code from `code_len` on and constants from `constant_count` on,
+    /// for when the compiler replaces code it has already emitted.
+    pub fn truncate(&mut self, code_len: usize, constant_count: usize) {
+        self.code.truncate(code_len);
+        while self.lines.last().is_some_and(|l| l.offset >= code_len) {
+            self.lines.pop();
+        }
+        self.constants.values.truncate(constant_count);
+        self.constant_indices
+            .retain(|_, &mut idx| idx < constant_count);
+    }
+
+    pub fn get_line(&self, offset: usize) -> usize {
+        let idx = self.lines.partition_point(|l| l.offset <= offset);
+        self.lines[idx - 1].line
     }
 
     pub fn write_constant(&mut self, value: Value, line: usize) {
         let idx = self.add_constant(value);
+        self.write_constant_index(idx, line);
+    }
+
+    pub fn write_constant_index(&mut self, idx: usize, line: usize) {
         if idx <= u8::MAX as usize {
             self.write(OpCode::Constant as u8, line);
             self.write(idx as u8, line);
@@ -61,104 +203,209 @@ impl Chunk {
         }
     }
 
-    pub fn disassemble(&self, name: &str) {
-        println!("== {name} ==");
-
-        let mut offset = 0;
-        while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+    /// Decodes the instructions in the chunk, in order.
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions {
+            chunk: self,
+            offset: 0,
         }
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{:04} ", offset);
+    /// Decodes the instruction starting at `offset`.
+    pub fn instruction_at(&self, offset: usize) -> Option<Instruction<'_>> {
+        let &byte = self.code.get(offset)?;
+        let opcode = OpCode::from_u8(byte);
+        let end = (offset + 1 + opcode.map_or(0, OpCode::operand_bytes)).min(self.code.len());
+        Some(Instruction {
+            offset,
+            line: self.get_line(offset),
+            opcode,
+            operands: &self.code[offset + 1..end],
+        })
+    }
 
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ");
+    pub fn disassemble(&self, out: &mut dyn Write, name: &str) -> io::Result<()> {
+        writeln!(out, "== {name} ==")?;
+        for instruction in self.instructions() {
+            self.write_instruction(out, &instruction)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the listing line for one instruction of this chunk.
+    pub fn write_instruction(
+        &self,
+        out: &mut dyn Write,
+        instruction: &Instruction,
+    ) -> io::Result<()> {
+        let offset = instruction.offset;
+        write!(out, "{:04} ", offset)?;
+        if offset > 0 && instruction.line == self.get_line(offset - 1) {
+            write!(out, "   | ")?;
         } else {
-            print!("{:4} ", self.lines[offset]);
+            write!(out, "{:4} ", instruction.line)?;
         }
 
-        match OpCode::from_u8(self.code[offset]) {
-            Some(OpCode::Constant) => {
-                let constant = self.code[offset + 1] as usize;
-                println!(
-                    "{:<16} {:4} '{}'",
-                    "Constant", constant, self.constants.values[constant]
-                );
-                offset + 2
-            }
-            Some(OpCode::ConstantLong) => {
-                let constant = ((self.code[offset + 1] as usize) << 16)
-                    | ((self.code[offset + 2] as usize) << 8)
-                    | (self.code[offset + 3] as usize);
-                println!(
-                    "{:<16} {:4} '{}'",
-                    "ConstantLong", constant, self.constants.values[constant]
-                );
-                offset + 4
-            }
-            Some(OpCode::Nil) => {
-                println!("Nil");
-                offset + 1
-            }
-            Some(OpCode::True) => {
-                println!("True");
-                offset + 1
-            }
-            Some(OpCode::False) => {
-                println!("False");
-                offset + 1
-            }
-            Some(OpCode::Equal) => {
-                println!("Equal");
-                offset + 1
-            }
-            Some(OpCode::Greater) => {
-                println!("Greater");
-                offset + 1
-            }
-            Some(OpCode::Less) => {
-                println!("Less");
-                offset + 1
-            }
-            Some(OpCode::Add) => {
-                println!("Add");
-                offset + 1
-            }
-            Some(OpCode::Subtract) => {
-                println!("Subtract");
-                offset + 1
-            }
-            Some(OpCode::Multiply) => {
-                println!("Multiply");
-                offset + 1
-            }
-            Some(OpCode::Divide) => {
-                println!("Divide");
-                offset + 1
-            }
-            Some(OpCode::Not) => {
-                println!("Not");
-                offset + 1
-            }
-            Some(OpCode::Negate) => {
-                println!("Negate");
-                offset + 1
+        let Some(op) = instruction.opcode else {
+            return writeln!(out, "Unknown opcode {}", self.code[offset]);
+        };
+        let name = format!("{:?}", op);
+        if instruction.operands.len() < op.operand_bytes() {
+            return writeln!(out, "{:<16} <truncated>", name);
+        }
+
+        let constant = |idx: usize| match self.constants.values.get(idx) {
+            Some(value) => format!("'{}'", value),
+            None => "<invalid>".to_string(),
+        };
+        match op {
+            OpCode::Constant | OpCode::ConstantLong | OpCode::AddConstant => {
+                let idx = instruction.constant().unwrap();
+                writeln!(out, "{:<16} {:4} {}", name, idx, constant(idx))
             }
-            Some(OpCode::Return) => {
-                println!("Return");
-                offset + 1
+            OpCode::BuildList | OpCode::BuildMap => {
+                writeln!(out, "{:<16} {:4}", name, instruction.operands[0])
             }
-            None => {
-                println!("Unknown opcode {}", self.code[offset]);
-                offset + 1
+            OpCode::Invoke => {
+                let idx = instruction.constant().unwrap();
+                let arg_count = instruction.operands[1];
+                writeln!(
+                    out,
+                    "{:<16} ({} args) {:4} {}",
+                    name,
+                    arg_count,
+                    idx,
+                    constant(idx)
+                )
             }
+            _ => writeln!(out, "{}", name),
         }
     }
 
-    fn add_constant(&mut self, value: Value) -> usize {
+    /// Adds `value` to the constant pool, reusing the slot of an identical
+    /// number or string constant if there is one.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = unsafe { Self::constant_key(&value) };
+        if let Some(key) = &key
+            && let Some(&idx) = self.constant_indices.get(key)
+        {
+            return idx;
+        }
+
         self.constants.write(value);
-        self.constants.values.len() - 1
+        let idx = self.constants.values.len() - 1;
+        if let Some(key) = key {
+            self.constant_indices.insert(key, idx);
+        }
+        idx
+    }
+
+    /// Index of an existing string constant, so callers can skip allocating a
+    /// duplicate string object.
+    pub fn find_string(&self, s: &str) -> Option<usize> {
+        self.constant_indices
+            .get(&ConstantKey::String(s.to_string()))
+            .copied()
+    }
+
+    unsafe fn constant_key(value: &Value) -> Option<ConstantKey> {
+        match value.unpack() {
+            Unpacked::Int(n) => Some(ConstantKey::Int(n)),
+            Unpacked::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            Unpacked::Obj(obj) if (*obj).kind == ObjKind::String => Some(ConstantKey::String(
+                (*(obj as *mut ObjString)).value.clone(),
+            )),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::rvm::RVM;
+
+    fn opcodes(chunk: &Chunk) -> Vec<OpCode> {
+        chunk
+            .instructions()
+            .map(|instruction| instruction.opcode.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn repeated_literals_share_one_constant() {
+        // The leading index keeps the sum from folding into one constant
+        let source = std::iter::once("[0][0]")
+            .chain(["1"; 300])
+            .chain(["\"s\""; 300])
+            .collect::<Vec<_>>()
+            .join(" + ");
+        let mut vm = RVM::new();
+        let mut chunk = Chunk::new();
+        assert!(compile(&mut vm, &source, &mut chunk));
+
+        assert_eq!(chunk.constants.values.len(), 3);
+        let loads = |wanted: &str| {
+            let slot = chunk
+                .constants
+                .values
+                .iter()
+                .position(|v| v.to_string() == wanted)
+                .unwrap();
+            chunk
+                .instructions()
+                .filter(|instruction| instruction.constant() == Some(slot))
+                .count()
+        };
+        assert_eq!(loads("1"), 300);
+        assert_eq!(loads("s"), 300);
+        assert!(opcodes(&chunk)
+            .iter()
+            .all(|op| !matches!(op, OpCode::ConstantLong)));
+    }
+
+    #[test]
+    fn floats_are_deduplicated_by_bit_pattern() {
+        let mut chunk = Chunk::new();
+        let nan = chunk.add_constant(Value::number(f64::NAN));
+        let zero = chunk.add_constant(Value::number(0.0));
+        let neg_zero = chunk.add_constant(Value::number(-0.0));
+        let int_zero = chunk.add_constant(Value::int(0));
+
+        assert_eq!(chunk.add_constant(Value::number(f64::NAN)), nan);
+        assert_eq!(chunk.add_constant(Value::number(-0.0)), neg_zero);
+        assert_ne!(zero, neg_zero);
+        assert_ne!(zero, int_zero);
+    }
+
+    #[test]
+    fn lists_instructions_with_operands() {
+        let mut vm = RVM::new();
+        let mut chunk = Chunk::new();
+        assert!(compile(&mut vm, "[1, 2][0]\n+ \"a\".len(\n)", &mut chunk));
+
+        let mut listing = vec![];
+        chunk.disassemble(&mut listing, "test").unwrap();
+        assert_eq!(
+            String::from_utf8(listing).unwrap(),
+            "== test ==\n\
+             0000    1 Constant            0 '1'\n\
+             0002    | Constant            1 '2'\n\
+             0004    | BuildList           2\n\
+             0006    | Constant            2 '0'\n\
+             0008    | IndexGet\n\
+             0009    2 Constant            3 'a'\n\
+             0011    3 Invoke           (0 args)    4 'len'\n\
+             0014    | Add\n\
+             0015    | Return\n"
+        );
+
+        let mut truncated = Chunk::new();
+        truncated.write(OpCode::Constant as u8, 1);
+        let instruction = truncated.instruction_at(0).unwrap();
+        assert!(instruction.operands.is_empty());
+        assert_eq!(instruction.constant(), None);
+        assert_eq!(truncated.instructions().count(), 1);
     }
 }
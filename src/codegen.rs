@@ -0,0 +1,255 @@
+use crate::ast::{Expr, ExprKind, Ident, Literal, Script};
+use crate::chunk::{Chunk, OpCode};
+use crate::compiler::{
+    constant_between, emit_folded, finish_chunk, report_error, string_constant, Mark,
+};
+use crate::fold;
+use crate::operators::concat_strings;
+use crate::parser::parse;
+use crate::resolver::resolve;
+use crate::rvm::RVM;
+use crate::scanner::TokenKind;
+use crate::value::Value;
+
+/// Compiles `source` by way of the tree: parse, resolve, then generate code.
+/// The chunk is the same, byte for byte and line for line, as the one
+/// `compiler::compile` produces.
+pub fn compile(vm: &mut RVM, source: &str, chunk: &mut Chunk) -> bool {
+    let Some(script) = parse(source) else {
+        return false;
+    };
+    if !resolve(&script) {
+        return false;
+    }
+    generate(vm, &script, chunk)
+}
+
+pub fn generate(vm: &mut RVM, script: &Script, chunk: &mut Chunk) -> bool {
+    let mut generator = Generator {
+        vm,
+        chunk,
+        had_error: false,
+    };
+    generator.expr(&script.body);
+    generator.chunk.write(OpCode::Return as u8, script.eof.line);
+    finish_chunk(generator.chunk, generator.had_error);
+
+    !generator.had_error
+}
+
+struct Generator<'v, 'c> {
+    vm: &'v mut RVM,
+    chunk: &'c mut Chunk,
+    had_error: bool,
+}
+
+impl Generator<'_, '_> {
+    /// Emits the code for `expr`. Each instruction goes on the line of the
+    /// last token of the node it belongs to.
+    fn expr(&mut self, expr: &Expr) {
+        let line = expr.span.end_line;
+        match &expr.kind {
+            ExprKind::Literal(literal) => self.literal(literal, line),
+            ExprKind::Grouping(inner) => self.expr(inner),
+            ExprKind::Unary { op, operand } => {
+                let start = Mark::of(self.chunk);
+                self.expr(operand);
+
+                if self.vm.folds_constants()
+                    && let Some(v) = constant_between(self.chunk, start, Mark::of(self.chunk))
+                    && let Some(value) = fold::unary(self.vm, *op, &v)
+                {
+                    emit_folded(self.chunk, start, value, line);
+                    return;
+                }
+
+                let op = match op {
+                    TokenKind::Bang => OpCode::Not,
+                    TokenKind::Minus => OpCode::Negate,
+                    TokenKind::Tilde => OpCode::BitNot,
+                    _ => unreachable!(),
+                };
+                self.chunk.write(op as u8, line);
+            }
+            ExprKind::Binary { op, lhs, rhs } => self.binary(*op, lhs, rhs, line),
+            ExprKind::List(items) => {
+                items.iter().for_each(|item| self.expr(item));
+                self.emit_bytes(OpCode::BuildList as u8, items.len() as u8, line);
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expr(key);
+                    self.expr(value);
+                }
+                self.emit_bytes(OpCode::BuildMap as u8, entries.len() as u8, line);
+            }
+            ExprKind::Index { target, index } => {
+                self.expr(target);
+                self.expr(index);
+                self.chunk.write(OpCode::IndexGet as u8, line);
+            }
+            ExprKind::IndexSet {
+                target,
+                index,
+                value,
+            } => {
+                self.expr(target);
+                self.expr(index);
+                self.expr(value);
+                self.chunk.write(OpCode::IndexSet as u8, line);
+            }
+            ExprKind::Invoke {
+                receiver,
+                name,
+                args,
+            } => {
+                self.expr(receiver);
+                let name = self.identifier_constant(name);
+                args.iter().for_each(|arg| self.expr(arg));
+                self.emit_bytes(OpCode::Invoke as u8, name, line);
+                self.chunk.write(args.len() as u8, line);
+            }
+            ExprKind::Error => unreachable!("the parser reported this node"),
+        }
+    }
+
+    fn binary(&mut self, op: TokenKind, lhs: &Expr, rhs: &Expr, line: usize) {
+        let lhs_start = Mark::of(self.chunk);
+        self.expr(lhs);
+        let rhs_start = Mark::of(self.chunk);
+        self.expr(rhs);
+
+        if self.vm.folds_constants()
+            && let Some(a) = constant_between(self.chunk, lhs_start, rhs_start)
+            && let Some(b) = constant_between(self.chunk, rhs_start, Mark::of(self.chunk))
+        {
+            if op == TokenKind::Plus
+                && let Some(s) = unsafe { concat_strings(&a, &b) }
+            {
+                lhs_start.truncate(self.chunk);
+                let idx = string_constant(self.vm, self.chunk, &s);
+                self.chunk.write_constant_index(idx, line);
+                return;
+            }
+            if let Some(value) = fold::binary(self.vm, op, &a, &b) {
+                emit_folded(self.chunk, lhs_start, value, line);
+                return;
+            }
+        }
+
+        let (first, second) = match op {
+            TokenKind::BangEqual => (OpCode::Equal, Some(OpCode::Not)),
+            TokenKind::EqualEqual => (OpCode::Equal, None),
+            TokenKind::Greater => (OpCode::Greater, None),
+            TokenKind::GreaterEqual => (OpCode::Less, Some(OpCode::Not)),
+            TokenKind::Less => (OpCode::Less, None),
+            TokenKind::LessEqual => (OpCode::Greater, Some(OpCode::Not)),
+            TokenKind::DotDot => (OpCode::Range, None),
+            TokenKind::DotDotEqual => (OpCode::RangeInclusive, None),
+            TokenKind::Plus => (OpCode::Add, None),
+            TokenKind::Minus => (OpCode::Subtract, None),
+            TokenKind::Star => (OpCode::Multiply, None),
+            TokenKind::Slash => (OpCode::Divide, None),
+            TokenKind::SlashSlash => (OpCode::IntDivide, None),
+            TokenKind::Percent => (OpCode::Modulo, None),
+            TokenKind::StarStar => (OpCode::Power, None),
+            TokenKind::Ampersand => (OpCode::BitAnd, None),
+            TokenKind::Pipe => (OpCode::BitOr, None),
+            TokenKind::Caret => (OpCode::BitXor, None),
+            TokenKind::LessLess => (OpCode::ShiftLeft, None),
+            TokenKind::GreaterGreater => (OpCode::ShiftRight, None),
+            _ => unreachable!(),
+        };
+        self.chunk.write(first as u8, line);
+        if let Some(second) = second {
+            self.chunk.write(second as u8, line);
+        }
+    }
+
+    fn literal(&mut self, literal: &Literal, line: usize) {
+        match literal {
+            Literal::Nil => self.chunk.write(OpCode::Nil as u8, line),
+            Literal::Bool(true) => self.chunk.write(OpCode::True as u8, line),
+            Literal::Bool(false) => self.chunk.write(OpCode::False as u8, line),
+            Literal::Int(n) => self.chunk.write_constant(self.vm.int(*n), line),
+            Literal::Number(n) => self.chunk.write_constant(Value::number(*n), line),
+            Literal::String(s) => {
+                let idx = string_constant(self.vm, self.chunk, s);
+                self.chunk.write_constant_index(idx, line);
+            }
+        }
+    }
+
+    fn identifier_constant(&mut self, name: &Ident) -> u8 {
+        let idx = string_constant(self.vm, self.chunk, name.name);
+        if idx > u8::MAX as usize {
+            self.error(name, "Too many constants in one chunk");
+            return 0;
+        }
+        idx as u8
+    }
+
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8, line: usize) {
+        self.chunk.write(byte1, line);
+        self.chunk.write(byte2, line);
+    }
+
+    fn error(&mut self, at: &Ident, message: &str) {
+        report_error(TokenKind::Identifier, at.name, at.span.line, message);
+        self.had_error = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::Chunk;
+    use crate::rvm::RVM;
+
+    fn compile_both(source: &str) -> (Chunk, Chunk) {
+        let mut vm = RVM::new();
+        let mut single_pass = Chunk::new();
+        let mut via_tree = Chunk::new();
+        assert!(crate::compiler::compile(&mut vm, source, &mut single_pass));
+        assert!(super::compile(&mut vm, source, &mut via_tree));
+        (single_pass, via_tree)
+    }
+
+    #[test]
+    fn matches_single_pass_compiler() {
+        let sources = [
+            "1 + 2 * 3",
+            "-(1 + 2) ** 2 // 3 % 4 - 5 / 2.0",
+            "[1, [2][0], 3,][0] + 4",
+            "{\"a\": 1, [2][0]: (\"b\"\n+ \"c\")}",
+            "[1, 2, 3][1] = [4\n][0]\n+ 5",
+            "\"abc\".len() + [1, 2].pop(\n0\n)",
+            "(1 .. 10).contains(5) == !([1][0] < 2) != ([3][0] >= 4)",
+            "~[12][0] & 10 | 1 << 4 ^ 7 >> 1",
+            "[\"a\"][0] + \"a\" + \"b\"\n\n",
+            "1 // 0 + 9223372036854775807 + 1",
+            "[0.0 / 0.0, nil, true, false, -0.0, 1 ..= 3][2]",
+        ];
+
+        for source in sources {
+            let (single_pass, via_tree) = compile_both(source);
+            assert_eq!(single_pass.code, via_tree.code, "{source}");
+
+            let lines = |chunk: &Chunk| {
+                (0..chunk.code.len())
+                    .map(|offset| chunk.get_line(offset))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(lines(&single_pass), lines(&via_tree), "{source}");
+
+            let constants = |chunk: &Chunk| {
+                chunk
+                    .constants
+                    .values
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(constants(&single_pass), constants(&via_tree), "{source}");
+        }
+    }
+}
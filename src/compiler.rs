@@ -1,5 +1,7 @@
 use crate::chunk::{Chunk, OpCode};
+use crate::fold;
 use crate::object::ObjString;
+use crate::operators::concat_strings;
 use crate::rvm::RVM;
 use crate::scanner::{Scanner, Token, TokenKind};
 use crate::value::Value;
@@ -12,12 +14,14 @@ pub fn compile(vm: &mut RVM, source: &str, chunk: &mut Chunk) -> bool {
     let mut scanner = Scanner::new(source);
     let mut parser = Parser::new();
 
-    parser.advance(&mut scanner);
+    parser.tokens.advance(&mut scanner);
     parser.expression(vm, &mut scanner, chunk);
-    parser.consume(&mut scanner, TokenKind::Eof, "Expect end of expression");
+    parser
+        .tokens
+        .consume(&mut scanner, TokenKind::Eof, "Expect end of expression");
     parser.end_compiler(chunk);
 
-    !parser.had_error
+    !parser.tokens.had_error
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd, FromPrimitive)]
@@ -29,27 +33,29 @@ pub enum Precedence {
     And,        // and
     Equality,   // == !=
     Comparison, // < > <= >=
+    Range,      // .. ..=
+    BitOr,      // |
+    BitXor,     // ^
+    BitAnd,     // &
+    Shift,      // << >>
     Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
+    Factor,     // * / // %
+    Unary,      // ! - ~
+    Exponent,   // **
     Call,       // . ()
     Primary,
 }
 
-type ParserRuleFn<'a> = fn(&mut Parser<'a>, &mut RVM, &mut Scanner<'a>, &mut Chunk);
+type ParserRuleFn<'a> = fn(&mut Parser<'a>, &mut RVM, &mut Scanner<'a>, &mut Chunk, bool);
 
-pub struct ParseRule<'a> {
-    prefix: Option<ParserRuleFn<'a>>,
-    infix: Option<ParserRuleFn<'a>>,
-    precedence: Precedence,
+pub struct ParseRule<P, I> {
+    pub prefix: Option<P>,
+    pub infix: Option<I>,
+    pub precedence: Precedence,
 }
 
-impl<'a> ParseRule<'a> {
-    pub fn new(
-        prefix: Option<ParserRuleFn<'a>>,
-        infix: Option<ParserRuleFn<'a>>,
-        precedence: Precedence,
-    ) -> Self {
+impl<P, I> ParseRule<P, I> {
+    pub fn new(prefix: Option<P>, infix: Option<I>, precedence: Precedence) -> Self {
         Self {
             prefix,
             infix,
@@ -58,67 +64,130 @@ impl<'a> ParseRule<'a> {
     }
 }
 
-pub struct Parser<'a> {
-    curr: Token<'a>,
-    prev: Token<'a>,
-    had_error: bool,
-    panic_mode: bool,
-    rules: HashMap<TokenKind, ParseRule<'a>>,
+/// Precedence to parse the right operand of the binary operator `op` at,
+/// given the operator's own precedence.
+pub fn rhs_precedence(op: TokenKind, precedence: Precedence) -> Precedence {
+    // '**' is right-associative and its right operand may carry a unary
+    // operator, so it's parsed at Unary rather than one level above itself
+    if op == TokenKind::StarStar {
+        Precedence::Unary
+    } else {
+        Precedence::from_u8(precedence as u8 + 1).unwrap()
+    }
 }
 
-impl<'a> Parser<'a> {
-    #[rustfmt::skip]
-    pub fn new() -> Self {
+/// The prefix and infix functions of a Pratt parser for the grammar. The
+/// single-pass compiler and the tree parser in `parser` each implement this,
+/// so the precedence and the rule for every token live in `rules` alone.
+pub trait Rules {
+    type Prefix;
+    type Infix;
+
+    const GROUPING: Self::Prefix;
+    const LIST: Self::Prefix;
+    const MAP: Self::Prefix;
+    const UNARY: Self::Prefix;
+    const NUMBER: Self::Prefix;
+    const STRING: Self::Prefix;
+    const LITERAL: Self::Prefix;
+    const BINARY: Self::Infix;
+    const SUBSCRIPT: Self::Infix;
+    const DOT: Self::Infix;
+}
+
+#[rustfmt::skip]
+pub fn rules<R: Rules>() -> HashMap<TokenKind, ParseRule<R::Prefix, R::Infix>> {
+    HashMap::from([
+        (TokenKind::LeftParen,      ParseRule::new(Some(R::GROUPING), None,               Precedence::None)),
+        (TokenKind::RightParen,     ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::LeftBrace,      ParseRule::new(Some(R::MAP),      None,               Precedence::None)),
+        (TokenKind::RightBrace,     ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::LeftBracket,    ParseRule::new(Some(R::LIST),     Some(R::SUBSCRIPT), Precedence::Call)),
+        (TokenKind::RightBracket,   ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::Comma,          ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::DotDot,         ParseRule::new(None,              Some(R::BINARY),    Precedence::Range)),
+        (TokenKind::DotDotEqual,    ParseRule::new(None,              Some(R::BINARY),    Precedence::Range)),
+        (TokenKind::Colon,          ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::Dot,            ParseRule::new(None,              Some(R::DOT),       Precedence::Call)),
+        (TokenKind::Minus,          ParseRule::new(Some(R::UNARY),    Some(R::BINARY),    Precedence::Term)),
+        (TokenKind::Plus,           ParseRule::new(None,              Some(R::BINARY),    Precedence::Term)),
+        (TokenKind::Semicolon,      ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::Slash,          ParseRule::new(None,              Some(R::BINARY),    Precedence::Factor)),
+        (TokenKind::SlashSlash,     ParseRule::new(None,              Some(R::BINARY),    Precedence::Factor)),
+        (TokenKind::Star,           ParseRule::new(None,              Some(R::BINARY),    Precedence::Factor)),
+        (TokenKind::StarStar,       ParseRule::new(None,              Some(R::BINARY),    Precedence::Exponent)),
+        (TokenKind::Percent,        ParseRule::new(None,              Some(R::BINARY),    Precedence::Factor)),
+        (TokenKind::Ampersand,      ParseRule::new(None,              Some(R::BINARY),    Precedence::BitAnd)),
+        (TokenKind::Pipe,           ParseRule::new(None,              Some(R::BINARY),    Precedence::BitOr)),
+        (TokenKind::Caret,          ParseRule::new(None,              Some(R::BINARY),    Precedence::BitXor)),
+        (TokenKind::Tilde,          ParseRule::new(Some(R::UNARY),    None,               Precedence::None)),
+        (TokenKind::Bang,           ParseRule::new(Some(R::UNARY),    None,               Precedence::None)),
+        (TokenKind::BangEqual,      ParseRule::new(None,              Some(R::BINARY),    Precedence::Equality)),
+        (TokenKind::Equal,          ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::EqualEqual,     ParseRule::new(None,              Some(R::BINARY),    Precedence::Equality)),
+        (TokenKind::Greater,        ParseRule::new(None,              Some(R::BINARY),    Precedence::Comparison)),
+        (TokenKind::GreaterEqual,   ParseRule::new(None,              Some(R::BINARY),    Precedence::Comparison)),
+        (TokenKind::GreaterGreater, ParseRule::new(None,              Some(R::BINARY),    Precedence::Shift)),
+        (TokenKind::Less,           ParseRule::new(None,              Some(R::BINARY),    Precedence::Comparison)),
+        (TokenKind::LessEqual,      ParseRule::new(None,              Some(R::BINARY),    Precedence::Comparison)),
+        (TokenKind::LessLess,       ParseRule::new(None,              Some(R::BINARY),    Precedence::Shift)),
+        (TokenKind::Identifier,     ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::String,         ParseRule::new(Some(R::STRING),   None,               Precedence::None)),
+        (TokenKind::Number,         ParseRule::new(Some(R::NUMBER),   None,               Precedence::None)),
+        (TokenKind::And,            ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::Class,          ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::Else,           ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::False,          ParseRule::new(Some(R::LITERAL),  None,               Precedence::None)),
+        (TokenKind::For,            ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::Fun,            ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::If,             ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::Nil,            ParseRule::new(Some(R::LITERAL),  None,               Precedence::None)),
+        (TokenKind::Or,             ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::Print,          ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::Return,         ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::Super,          ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::This,           ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::True,           ParseRule::new(Some(R::LITERAL),  None,               Precedence::None)),
+        (TokenKind::Var,            ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::While,          ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::Error,          ParseRule::new(None,              None,               Precedence::None)),
+        (TokenKind::Eof,            ParseRule::new(None,              None,               Precedence::None)),
+    ])
+}
+
+/// Chunk position at the start of an operand, so code for an operand that
+/// folds to a constant can be cut back out.
+#[derive(Copy, Clone, Default)]
+pub struct Mark {
+    code: usize,
+    constants: usize,
+}
+
+impl Mark {
+    pub fn of(chunk: &Chunk) -> Self {
         Self {
-            curr: Token::default(),
-            prev: Token::default(),
-            had_error: false,
-            panic_mode: false,
-            rules: HashMap::from([
-                (TokenKind::LeftParen,    ParseRule::new(Some(Self::grouping), None,               Precedence::None)),
-                (TokenKind::RightParen,   ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::LeftBrace,    ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::RightBrace,   ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::Comma,        ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::Dot,          ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::Minus,        ParseRule::new(Some(Self::unary),    Some(Self::binary), Precedence::Term)),
-                (TokenKind::Plus,         ParseRule::new(None,                 Some(Self::binary), Precedence::Term)),
-                (TokenKind::Semicolon,    ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::Slash,        ParseRule::new(None,                 Some(Self::binary), Precedence::Factor)),
-                (TokenKind::Star,         ParseRule::new(None,                 Some(Self::binary), Precedence::Factor)),
-                (TokenKind::Bang,         ParseRule::new(Some(Self::unary),    None,               Precedence::None)),
-                (TokenKind::BangEqual,    ParseRule::new(None,                 Some(Self::binary), Precedence::Equality)),
-                (TokenKind::Equal,        ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::EqualEqual,   ParseRule::new(None,                 Some(Self::binary), Precedence::Equality)),
-                (TokenKind::Greater,      ParseRule::new(None,                 Some(Self::binary), Precedence::Comparison)),
-                (TokenKind::GreaterEqual, ParseRule::new(None,                 Some(Self::binary), Precedence::Comparison)),
-                (TokenKind::Less,         ParseRule::new(None,                 Some(Self::binary), Precedence::Comparison)),
-                (TokenKind::LessEqual,    ParseRule::new(None,                 Some(Self::binary), Precedence::Comparison)),
-                (TokenKind::Identifier,   ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::String,       ParseRule::new(Some(Self::string),   None,               Precedence::None)),
-                (TokenKind::Number,       ParseRule::new(Some(Self::number),   None,               Precedence::None)),
-                (TokenKind::And,          ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::Class,        ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::Else,         ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::False,        ParseRule::new(Some(Self::literal),  None,               Precedence::None)),
-                (TokenKind::For,          ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::Fun,          ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::If,           ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::Nil,          ParseRule::new(Some(Self::literal),  None,               Precedence::None)),
-                (TokenKind::Or,           ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::Print,        ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::Return,       ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::Super,        ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::This,         ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::True,         ParseRule::new(Some(Self::literal),  None,               Precedence::None)),
-                (TokenKind::Var,          ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::While,        ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::Error,        ParseRule::new(None,                 None,               Precedence::None)),
-                (TokenKind::Eof,          ParseRule::new(None,                 None,               Precedence::None)),
-            ])
+            code: chunk.code.len(),
+            constants: chunk.constants.values.len(),
         }
     }
 
+    /// Cuts `chunk` back to this position.
+    pub fn truncate(self, chunk: &mut Chunk) {
+        chunk.truncate(self.code, self.constants);
+    }
+}
+
+/// The current and previous token and the error state of a parse, with the
+/// helpers both Pratt parsers use to step through the tokens.
+#[derive(Default)]
+pub struct Tokens<'a> {
+    pub curr: Token<'a>,
+    pub prev: Token<'a>,
+    pub had_error: bool,
+    panic_mode: bool,
+}
+
+impl<'a> Tokens<'a> {
     pub fn advance(&mut self, scanner: &mut Scanner<'a>) {
         self.prev = take(&mut self.curr);
 
@@ -140,8 +209,70 @@ impl<'a> Parser<'a> {
         }
     }
 
+    pub fn check(&self, kind: TokenKind) -> bool {
+        self.curr.kind == kind
+    }
+
+    pub fn match_token(&mut self, scanner: &mut Scanner<'a>, kind: TokenKind) -> bool {
+        if !self.check(kind) {
+            false
+        } else {
+            self.advance(scanner);
+            true
+        }
+    }
+
+    fn error_at_curr(&mut self, message: &'a str) {
+        self.error_at(self.curr.kind, self.curr.lexeme, self.curr.line, message);
+    }
+
+    pub fn error(&mut self, message: &'a str) {
+        self.error_at(self.prev.kind, self.prev.lexeme, self.prev.line, message);
+    }
+
+    fn error_at(&mut self, kind: TokenKind, lexeme: &str, line: usize, message: &'a str) {
+        if self.panic_mode {
+            return;
+        }
+        report_error(kind, lexeme, line, message);
+        self.had_error = true;
+    }
+}
+
+pub struct Parser<'a> {
+    tokens: Tokens<'a>,
+    rules: HashMap<TokenKind, ParseRule<ParserRuleFn<'a>, ParserRuleFn<'a>>>,
+    /// Start of the left operand of the infix rule being parsed
+    lhs_start: Mark,
+}
+
+impl<'a> Rules for Parser<'a> {
+    type Prefix = ParserRuleFn<'a>;
+    type Infix = ParserRuleFn<'a>;
+
+    const GROUPING: ParserRuleFn<'a> = Self::grouping;
+    const LIST: ParserRuleFn<'a> = Self::list;
+    const MAP: ParserRuleFn<'a> = Self::map;
+    const UNARY: ParserRuleFn<'a> = Self::unary;
+    const NUMBER: ParserRuleFn<'a> = Self::number;
+    const STRING: ParserRuleFn<'a> = Self::string;
+    const LITERAL: ParserRuleFn<'a> = Self::literal;
+    const BINARY: ParserRuleFn<'a> = Self::binary;
+    const SUBSCRIPT: ParserRuleFn<'a> = Self::subscript;
+    const DOT: ParserRuleFn<'a> = Self::dot;
+}
+
+impl<'a> Parser<'a> {
+    pub fn new() -> Self {
+        Self {
+            tokens: Tokens::default(),
+            rules: rules::<Self>(),
+            lhs_start: Mark::default(),
+        }
+    }
+
     fn emit_byte(&self, chunk: &mut Chunk, byte: u8) {
-        chunk.write(byte, self.prev.line);
+        chunk.write(byte, self.tokens.prev.line);
     }
 
     fn emit_bytes(&self, chunk: &mut Chunk, byte1: u8, byte2: u8) {
@@ -154,28 +285,53 @@ impl<'a> Parser<'a> {
     }
 
     fn emit_constant(&self, chunk: &mut Chunk, value: Value) {
-        chunk.write_constant(value, self.prev.line);
+        chunk.write_constant(value, self.tokens.prev.line);
+    }
+
+    fn identifier_constant(&mut self, vm: &mut RVM, chunk: &mut Chunk) -> u8 {
+        let idx = string_constant(vm, chunk, self.tokens.prev.lexeme);
+        if idx > u8::MAX as usize {
+            self.tokens.error("Too many constants in one chunk");
+            return 0;
+        }
+        idx as u8
     }
 
     fn end_compiler(&self, chunk: &mut Chunk) {
         self.emit_return(chunk);
-        #[cfg(feature = "debug-print-code")]
+        finish_chunk(chunk, self.tokens.had_error);
+    }
+
+    fn binary(
+        &mut self,
+        vm: &mut RVM,
+        scanner: &mut Scanner<'a>,
+        chunk: &mut Chunk,
+        _can_assign: bool,
+    ) {
+        let op_kind = self.tokens.prev.kind;
+        let precedence = rhs_precedence(op_kind, self.rules[&op_kind].precedence);
+        let lhs_start = self.lhs_start;
+        let rhs_start = Mark::of(chunk);
+        self.parse_precedence(vm, scanner, chunk, precedence);
+
+        if vm.folds_constants()
+            && let Some(a) = constant_between(chunk, lhs_start, rhs_start)
+            && let Some(b) = constant_between(chunk, rhs_start, Mark::of(chunk))
         {
-            if !self.had_error {
-                chunk.disassemble("code");
+            if op_kind == TokenKind::Plus
+                && let Some(s) = unsafe { concat_strings(&a, &b) }
+            {
+                lhs_start.truncate(chunk);
+                let idx = string_constant(vm, chunk, &s);
+                chunk.write_constant_index(idx, self.tokens.prev.line);
+                return;
+            }
+            if let Some(value) = fold::binary(vm, op_kind, &a, &b) {
+                emit_folded(chunk, lhs_start, value, self.tokens.prev.line);
+                return;
             }
         }
-    }
-
-    fn binary(&mut self, vm: &mut RVM, scanner: &mut Scanner<'a>, chunk: &mut Chunk) {
-        let op_kind = self.prev.kind;
-        let rule = &self.rules[&op_kind];
-        self.parse_precedence(
-            vm,
-            scanner,
-            chunk,
-            Precedence::from_u8(rule.precedence as u8 + 1).unwrap(),
-        );
 
         match op_kind {
             TokenKind::BangEqual => self.emit_bytes(chunk, OpCode::Equal as u8, OpCode::Not as u8),
@@ -188,16 +344,170 @@ impl<'a> Parser<'a> {
             TokenKind::LessEqual => {
                 self.emit_bytes(chunk, OpCode::Greater as u8, OpCode::Not as u8)
             }
+            TokenKind::DotDot => self.emit_byte(chunk, OpCode::Range as u8),
+            TokenKind::DotDotEqual => self.emit_byte(chunk, OpCode::RangeInclusive as u8),
             TokenKind::Plus => self.emit_byte(chunk, OpCode::Add as u8),
             TokenKind::Minus => self.emit_byte(chunk, OpCode::Subtract as u8),
             TokenKind::Star => self.emit_byte(chunk, OpCode::Multiply as u8),
             TokenKind::Slash => self.emit_byte(chunk, OpCode::Divide as u8),
+            TokenKind::SlashSlash => self.emit_byte(chunk, OpCode::IntDivide as u8),
+            TokenKind::Percent => self.emit_byte(chunk, OpCode::Modulo as u8),
+            TokenKind::StarStar => self.emit_byte(chunk, OpCode::Power as u8),
+            TokenKind::Ampersand => self.emit_byte(chunk, OpCode::BitAnd as u8),
+            TokenKind::Pipe => self.emit_byte(chunk, OpCode::BitOr as u8),
+            TokenKind::Caret => self.emit_byte(chunk, OpCode::BitXor as u8),
+            TokenKind::LessLess => self.emit_byte(chunk, OpCode::ShiftLeft as u8),
+            TokenKind::GreaterGreater => self.emit_byte(chunk, OpCode::ShiftRight as u8),
             _ => unreachable!(),
         }
     }
 
-    fn literal(&mut self, _vm: &mut RVM, _scanner: &mut Scanner<'a>, chunk: &mut Chunk) {
-        match self.prev.kind {
+    fn dot(
+        &mut self,
+        vm: &mut RVM,
+        scanner: &mut Scanner<'a>,
+        chunk: &mut Chunk,
+        _can_assign: bool,
+    ) {
+        self.tokens.consume(
+            scanner,
+            TokenKind::Identifier,
+            "Expect method name after '.'",
+        );
+        let name = self.identifier_constant(vm, chunk);
+
+        self.tokens.consume(
+            scanner,
+            TokenKind::LeftParen,
+            "Expect '(' after method name",
+        );
+        let arg_count = self.argument_list(vm, scanner, chunk);
+        self.emit_bytes(chunk, OpCode::Invoke as u8, name);
+        self.emit_byte(chunk, arg_count);
+    }
+
+    fn argument_list(&mut self, vm: &mut RVM, scanner: &mut Scanner<'a>, chunk: &mut Chunk) -> u8 {
+        let mut arg_count = 0;
+        if !self.tokens.check(TokenKind::RightParen) {
+            loop {
+                self.expression(vm, scanner, chunk);
+                if arg_count == u8::MAX {
+                    self.tokens.error("Can't have more than 255 arguments");
+                }
+                arg_count = arg_count.saturating_add(1);
+
+                if !self.tokens.match_token(scanner, TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.tokens
+            .consume(scanner, TokenKind::RightParen, "Expect ')' after arguments");
+        arg_count
+    }
+
+    fn list(
+        &mut self,
+        vm: &mut RVM,
+        scanner: &mut Scanner<'a>,
+        chunk: &mut Chunk,
+        _can_assign: bool,
+    ) {
+        let mut count = 0;
+        if !self.tokens.check(TokenKind::RightBracket) {
+            loop {
+                // Allow a trailing comma
+                if self.tokens.check(TokenKind::RightBracket) {
+                    break;
+                }
+
+                self.expression(vm, scanner, chunk);
+                if count == u8::MAX {
+                    self.tokens
+                        .error("Can't have more than 255 elements in a list literal");
+                }
+                count = count.saturating_add(1);
+
+                if !self.tokens.match_token(scanner, TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.tokens.consume(
+            scanner,
+            TokenKind::RightBracket,
+            "Expect ']' after list elements",
+        );
+        self.emit_bytes(chunk, OpCode::BuildList as u8, count);
+    }
+
+    fn map(
+        &mut self,
+        vm: &mut RVM,
+        scanner: &mut Scanner<'a>,
+        chunk: &mut Chunk,
+        _can_assign: bool,
+    ) {
+        // Statements don't exist yet, so a '{' in expression position is
+        // always a map literal and never a block
+        let mut count = 0;
+        if !self.tokens.check(TokenKind::RightBrace) {
+            loop {
+                // Allow a trailing comma
+                if self.tokens.check(TokenKind::RightBrace) {
+                    break;
+                }
+
+                self.expression(vm, scanner, chunk);
+                self.tokens
+                    .consume(scanner, TokenKind::Colon, "Expect ':' after map key");
+                self.expression(vm, scanner, chunk);
+                if count == u8::MAX {
+                    self.tokens
+                        .error("Can't have more than 255 entries in a map literal");
+                }
+                count = count.saturating_add(1);
+
+                if !self.tokens.match_token(scanner, TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.tokens.consume(
+            scanner,
+            TokenKind::RightBrace,
+            "Expect '}' after map entries",
+        );
+        self.emit_bytes(chunk, OpCode::BuildMap as u8, count);
+    }
+
+    fn subscript(
+        &mut self,
+        vm: &mut RVM,
+        scanner: &mut Scanner<'a>,
+        chunk: &mut Chunk,
+        can_assign: bool,
+    ) {
+        self.expression(vm, scanner, chunk);
+        self.tokens
+            .consume(scanner, TokenKind::RightBracket, "Expect ']' after index");
+
+        if can_assign && self.tokens.match_token(scanner, TokenKind::Equal) {
+            self.expression(vm, scanner, chunk);
+            self.emit_byte(chunk, OpCode::IndexSet as u8);
+        } else {
+            self.emit_byte(chunk, OpCode::IndexGet as u8);
+        }
+    }
+
+    fn literal(
+        &mut self,
+        _vm: &mut RVM,
+        _scanner: &mut Scanner<'a>,
+        chunk: &mut Chunk,
+        _can_assign: bool,
+    ) {
+        match self.tokens.prev.kind {
             TokenKind::False => self.emit_byte(chunk, OpCode::False as u8),
             TokenKind::Nil => self.emit_byte(chunk, OpCode::Nil as u8),
             TokenKind::True => self.emit_byte(chunk, OpCode::True as u8),
@@ -205,34 +515,80 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn grouping(&mut self, vm: &mut RVM, scanner: &mut Scanner<'a>, chunk: &mut Chunk) {
+    fn grouping(
+        &mut self,
+        vm: &mut RVM,
+        scanner: &mut Scanner<'a>,
+        chunk: &mut Chunk,
+        _can_assign: bool,
+    ) {
         self.expression(vm, scanner, chunk);
-        self.consume(
+        self.tokens.consume(
             scanner,
             TokenKind::RightParen,
             "Expect ')' after expression",
         );
     }
 
-    fn number(&mut self, _vm: &mut RVM, _scanner: &mut Scanner<'a>, chunk: &mut Chunk) {
-        self.emit_constant(chunk, Value::Number(self.prev.lexeme.parse().unwrap()));
+    fn number(
+        &mut self,
+        vm: &mut RVM,
+        _scanner: &mut Scanner<'a>,
+        chunk: &mut Chunk,
+        _can_assign: bool,
+    ) {
+        if self.tokens.prev.lexeme.contains('.') {
+            self.emit_constant(
+                chunk,
+                Value::number(self.tokens.prev.lexeme.parse().unwrap()),
+            );
+        } else {
+            match self.tokens.prev.lexeme.parse() {
+                Ok(n) => self.emit_constant(chunk, vm.int(n)),
+                Err(_) => self.tokens.error("Integer literal is too large"),
+            }
+        }
     }
 
-    fn string(&mut self, vm: &mut RVM, _scanner: &mut Scanner<'a>, chunk: &mut Chunk) {
-        let obj = ObjString::new(self.prev.lexeme[1..self.prev.lexeme.len() - 1].to_string());
-        unsafe {
-            self.emit_constant(chunk, Value::Obj(vm.allocate_obj(obj)));
-        }
+    fn string(
+        &mut self,
+        vm: &mut RVM,
+        _scanner: &mut Scanner<'a>,
+        chunk: &mut Chunk,
+        _can_assign: bool,
+    ) {
+        let idx = string_constant(
+            vm,
+            chunk,
+            &self.tokens.prev.lexeme[1..self.tokens.prev.lexeme.len() - 1],
+        );
+        chunk.write_constant_index(idx, self.tokens.prev.line);
     }
 
-    fn unary(&mut self, vm: &mut RVM, scanner: &mut Scanner<'a>, chunk: &mut Chunk) {
-        let op_kind = self.prev.kind;
+    fn unary(
+        &mut self,
+        vm: &mut RVM,
+        scanner: &mut Scanner<'a>,
+        chunk: &mut Chunk,
+        _can_assign: bool,
+    ) {
+        let op_kind = self.tokens.prev.kind;
+        let start = Mark::of(chunk);
 
         self.parse_precedence(vm, scanner, chunk, Precedence::Unary);
 
+        if vm.folds_constants()
+            && let Some(v) = constant_between(chunk, start, Mark::of(chunk))
+            && let Some(value) = fold::unary(vm, op_kind, &v)
+        {
+            emit_folded(chunk, start, value, self.tokens.prev.line);
+            return;
+        }
+
         match op_kind {
             TokenKind::Bang => self.emit_byte(chunk, OpCode::Not as u8),
             TokenKind::Minus => self.emit_byte(chunk, OpCode::Negate as u8),
+            TokenKind::Tilde => self.emit_byte(chunk, OpCode::BitNot as u8),
             _ => unreachable!(),
         }
     }
@@ -244,48 +600,97 @@ impl<'a> Parser<'a> {
         chunk: &mut Chunk,
         precedence: Precedence,
     ) {
-        self.advance(scanner);
-        match self.rules[&self.prev.kind].prefix {
+        self.tokens.advance(scanner);
+        let start = Mark::of(chunk);
+        let can_assign = precedence <= Precedence::Assignment;
+        match self.rules[&self.tokens.prev.kind].prefix {
             None => {
-                self.error("Expect expression");
+                self.tokens.error("Expect expression");
                 return;
             }
-            Some(f) => f(self, vm, scanner, chunk),
+            Some(f) => f(self, vm, scanner, chunk, can_assign),
         };
 
-        while precedence <= self.rules[&self.curr.kind].precedence {
-            self.advance(scanner);
-            self.rules[&self.prev.kind].infix.unwrap()(self, vm, scanner, chunk);
+        while precedence <= self.rules[&self.tokens.curr.kind].precedence {
+            self.tokens.advance(scanner);
+            self.lhs_start = start;
+            self.rules[&self.tokens.prev.kind].infix.unwrap()(self, vm, scanner, chunk, can_assign);
+        }
+
+        if can_assign && self.tokens.match_token(scanner, TokenKind::Equal) {
+            self.tokens.error("Invalid assignment target");
         }
     }
 
     pub fn expression(&mut self, vm: &mut RVM, scanner: &mut Scanner<'a>, chunk: &mut Chunk) {
         self.parse_precedence(vm, scanner, chunk, Precedence::Assignment);
     }
+}
 
-    fn error_at_curr(&mut self, message: &'a str) {
-        self.error_at(self.curr.kind, self.curr.lexeme, self.curr.line, message);
+/// Prints a compile error at the token `kind`/`lexeme` on `line`.
+pub fn report_error(kind: TokenKind, lexeme: &str, line: usize, message: &str) {
+    report("Error", kind, lexeme, line, message);
+}
+
+/// Prints a compile warning. Warnings don't stop compilation, so callers
+/// report them without setting `had_error`.
+pub fn report_warning(kind: TokenKind, lexeme: &str, line: usize, message: &str) {
+    report("Warning", kind, lexeme, line, message);
+}
+
+fn report(severity: &str, kind: TokenKind, lexeme: &str, line: usize, message: &str) {
+    eprint!("[line {}] {}", line, severity);
+
+    if kind == TokenKind::Eof {
+        eprint!(" at end");
+    } else if kind == TokenKind::Error {
+        // do nothing
+    } else {
+        eprint!(" at {}", lexeme);
     }
 
-    fn error(&mut self, message: &'a str) {
-        self.error_at(self.prev.kind, self.prev.lexeme, self.prev.line, message);
+    eprintln!(": {}", message);
+}
+
+/// Runs the passes that follow code generation on a chunk ending in `Return`.
+pub fn finish_chunk(chunk: &mut Chunk, had_error: bool) {
+    if !had_error && !cfg!(feature = "debug-no-peephole") {
+        crate::peephole::optimize(chunk);
     }
+}
 
-    fn error_at(&mut self, kind: TokenKind, lexeme: &str, line: usize, message: &'a str) {
-        if self.panic_mode {
-            return;
-        }
-        eprint!("[line {}] Error", line);
+pub fn string_constant(vm: &mut RVM, chunk: &mut Chunk, s: &str) -> usize {
+    chunk.find_string(s).unwrap_or_else(|| {
+        let obj = ObjString::new(s.to_string());
+        unsafe { chunk.add_constant(Value::obj(vm.allocate_obj(obj))) }
+    })
+}
 
-        if kind == TokenKind::Eof {
-            eprint!(" at end");
-        } else if kind == TokenKind::Error {
-            // do nothing
-        } else {
-            eprint!(" at {}", lexeme);
-        }
+/// Replaces the code from `start` on with a single load of `value`.
+pub fn emit_folded(chunk: &mut Chunk, start: Mark, value: Value, line: usize) {
+    start.truncate(chunk);
+    match value.as_bool() {
+        Some(true) => chunk.write(OpCode::True as u8, line),
+        Some(false) => chunk.write(OpCode::False as u8, line),
+        None => chunk.write_constant(value, line),
+    }
+}
 
-        eprintln!(": {}", message);
-        self.had_error = true;
+/// The value loaded if the code between `start` and `end` is a single
+/// constant load.
+pub fn constant_between(chunk: &Chunk, start: Mark, end: Mark) -> Option<Value> {
+    let instruction = chunk.instruction_at(start.code)?;
+    if instruction.next_offset() != end.code {
+        return None;
+    }
+
+    match instruction.opcode? {
+        OpCode::Constant | OpCode::ConstantLong => {
+            Some(chunk.constants.values[instruction.constant()?].clone())
+        }
+        OpCode::Nil => Some(Value::nil()),
+        OpCode::True => Some(Value::boolean(true)),
+        OpCode::False => Some(Value::boolean(false)),
+        _ => None,
     }
 }
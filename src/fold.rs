@@ -0,0 +1,165 @@
+use crate::operators::{BinaryOp, UnaryOp};
+use crate::rvm::RVM;
+use crate::scanner::TokenKind;
+use crate::value::Value;
+
+// Compile-time evaluation of operators on constant operands, using the same
+// functions as `RVM::run`. Anything the VM would report as a runtime error
+// isn't folded, so it's still reported at runtime with its line.
+
+pub fn unary(vm: &mut RVM, op: TokenKind, v: &Value) -> Option<Value> {
+    let op_fn: UnaryOp = match op {
+        TokenKind::Bang => return Some(Value::boolean(!v.is_falsey())),
+        TokenKind::Minus => RVM::negate,
+        TokenKind::Tilde => RVM::bit_not,
+        _ => return None,
+    };
+    op_fn(vm, v).ok()
+}
+
+pub fn binary(vm: &mut RVM, op: TokenKind, a: &Value, b: &Value) -> Option<Value> {
+    let op_fn: BinaryOp = match op {
+        TokenKind::EqualEqual => return Some(Value::boolean(a == b)),
+        TokenKind::BangEqual => return Some(Value::boolean(a != b)),
+        TokenKind::Greater => RVM::greater,
+        TokenKind::GreaterEqual => RVM::greater_equal,
+        TokenKind::Less => RVM::less,
+        TokenKind::LessEqual => RVM::less_equal,
+        TokenKind::Plus => RVM::add,
+        TokenKind::Minus => RVM::subtract,
+        TokenKind::Star => RVM::multiply,
+        TokenKind::Slash => RVM::divide,
+        TokenKind::SlashSlash => RVM::floor_divide,
+        TokenKind::Percent => RVM::modulo,
+        TokenKind::StarStar => RVM::power,
+        TokenKind::Ampersand => RVM::bit_and,
+        TokenKind::Pipe => RVM::bit_or,
+        TokenKind::Caret => RVM::bit_xor,
+        TokenKind::LessLess => RVM::shift_left,
+        TokenKind::GreaterGreater => RVM::shift_right,
+        _ => return None,
+    };
+    op_fn(vm, a, b).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{Chunk, OpCode};
+    use crate::compiler::compile;
+    use crate::rvm::{DebugOptions, RVM};
+
+    fn run(source: &str) -> (usize, Option<String>) {
+        let mut vm = RVM::new();
+        let mut chunk = Chunk::new();
+        assert!(compile(&mut vm, source, &mut chunk));
+        let result = unsafe { vm.interpret(&chunk) };
+        (chunk.code.len(), result.ok().map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn folding_matches_runtime() {
+        let operands = [
+            "7",
+            "-3",
+            "0",
+            "2.5",
+            "-0.0",
+            "(0.0 / 0.0)",
+            "9223372036854775807",
+            "9007199254740993",
+            "9007199254740992.0",
+            "(-9223372036854775807 - 1)",
+            "-1",
+            "\"s\"",
+            "true",
+            "nil",
+        ];
+        let ops = [
+            "+", "-", "*", "/", "//", "%", "**", "==", "!=", "<", ">", "<=", ">=", "&", "|", "^",
+            "<<", ">>",
+        ];
+
+        for a in operands {
+            for op in ops {
+                for b in operands {
+                    let folded = run(&format!("({a}) {op} ({b})"));
+                    let runtime = run(&format!("[{a}][0] {op} [{b}][0]"));
+                    assert_eq!(folded.1, runtime.1, "{a} {op} {b}");
+                }
+            }
+            for op in ["-", "!", "~"] {
+                let folded = run(&format!("{op}({a})"));
+                let runtime = run(&format!("{op}[{a}][0]"));
+                assert_eq!(folded.1, runtime.1, "{op}{a}");
+            }
+        }
+    }
+
+    #[test]
+    fn orders_ints_against_floats_exactly() {
+        for (source, expected) in [
+            ("9007199254740993 == 9007199254740992.0", "false"),
+            ("9007199254740993 >= 9007199254740992.0", "true"),
+            ("9007199254740993 <= 9007199254740992.0", "false"),
+            ("9007199254740992.0 < 9007199254740993", "true"),
+            ("9223372036854775807 < 9223372036854775808.0", "true"),
+        ] {
+            assert_eq!(run(source).1.as_deref(), Some(expected), "{source}");
+            let (lhs, rest) = source.split_once(' ').unwrap();
+            let runtime = format!("[{lhs}][0] {rest}");
+            assert_eq!(run(&runtime).1.as_deref(), Some(expected), "{runtime}");
+        }
+    }
+
+    #[test]
+    fn only_floor_division_overflows() {
+        let min = "(-9223372036854775807 - 1)";
+        assert_eq!(run(&format!("{min} % -1")).1.as_deref(), Some("0"));
+        assert_eq!(run(&format!("[{min}][0] % -1")).1.as_deref(), Some("0"));
+        assert_eq!(run(&format!("[{min}][0] // -1")).1, None);
+    }
+
+    #[test]
+    fn folds_to_one_load() {
+        let mut vm = RVM::new();
+        let mut chunk = Chunk::new();
+        assert!(compile(
+            &mut vm,
+            "-(1 + 2 * 3) ** 2 / 4 == \"a\" + \"b\"",
+            &mut chunk
+        ));
+        assert_eq!(chunk.code, [OpCode::False as u8, OpCode::Return as u8]);
+
+        let mut chunk = Chunk::new();
+        assert!(compile(
+            &mut vm,
+            "~(12 & 10 | 1 << 4) ^ 3 == -28",
+            &mut chunk
+        ));
+        assert_eq!(chunk.code, [OpCode::True as u8, OpCode::Return as u8]);
+
+        assert!(compile(
+            &mut vm,
+            "1 + 2.5 + \"a\" + \"b\"",
+            &mut Chunk::new()
+        ));
+        let mut chunk = Chunk::new();
+        assert!(compile(&mut vm, "\"a\" + \"b\" + 1", &mut chunk));
+        assert_eq!(chunk.constants.values.len(), 2);
+    }
+
+    #[test]
+    fn no_fold_keeps_every_operator() {
+        let mut vm = RVM::new();
+        vm.set_debug_options(DebugOptions {
+            no_fold: true,
+            ..DebugOptions::default()
+        });
+        let mut chunk = Chunk::new();
+        assert!(compile(&mut vm, "-(1 + 2) == \"a\" + \"b\"", &mut chunk));
+        assert_eq!(chunk.code.last(), Some(&(OpCode::Return as u8)));
+        assert!(chunk.code.contains(&(OpCode::Negate as u8)));
+        assert!(chunk.code.contains(&(OpCode::Equal as u8)));
+        assert_eq!(chunk.constants.values.len(), 4);
+    }
+}
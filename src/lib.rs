@@ -0,0 +1,30 @@
+#![feature(let_chains)]
+#![allow(dead_code)]
+
+mod ast;
+mod bytecode;
+mod chunk;
+mod codegen;
+mod common;
+mod compiler;
+mod fold;
+mod methods;
+mod object;
+mod operators;
+mod parser;
+mod peephole;
+mod resolver;
+mod rvm;
+mod scanner;
+mod value;
+mod verifier;
+
+pub use crate::bytecode::is_bytecode;
+pub use crate::chunk::Chunk;
+pub use crate::compiler::compile;
+pub use crate::rvm::{
+    compile_to_bytecode, dump_ast, interpret, interpret_bytecode, DebugOptions, InterpretErr, RVM,
+};
+pub use crate::scanner::is_incomplete;
+pub use crate::value::{Unpacked, Value};
+pub use crate::verifier::{verify, StackUsage};
@@ -1,66 +1,250 @@
-#![feature(let_chains)]
-#![allow(dead_code)]
-
-use crate::rvm::interpret;
-use clap::Parser;
-use std::io::Write;
+use rox::{
+    compile, compile_to_bytecode, dump_ast, interpret, interpret_bytecode, is_bytecode,
+    is_incomplete, Chunk, DebugOptions, RVM,
+};
+use clap::{Parser, Subcommand};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::{fs, io};
+use std::mem::take;
 use std::path::PathBuf;
 use std::process::exit;
-use std::{fs, io};
-
-mod chunk;
-mod common;
-mod compiler;
-mod rvm;
-mod scanner;
-mod value;
-mod object;
+use std::time::Instant;
 
 /// rox interpreter
 #[derive(Parser, Debug)]
-#[command(about, long_about = None)]
+#[command(about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Run file
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Run file (source or compiled bytecode)
     #[arg(required = false)]
     script: Option<PathBuf>,
+
+    /// Print the syntax tree of the script instead of running it
+    #[arg(long, requires = "script")]
+    dump_ast: bool,
+
+    /// Print the bytecode of each chunk before running it
+    #[arg(long)]
+    print_code: bool,
+
+    /// Show the stack and each instruction as it runs
+    #[arg(long)]
+    trace: bool,
+
+    /// Only trace the function with this name (the top level is "script")
+    #[arg(long, value_name = "FN")]
+    trace_filter: Option<String>,
+
+    /// Compile operators on constants as written instead of folding them
+    #[arg(long)]
+    no_fold: bool,
+
+    /// Write code listings and traces to this file instead of stderr
+    #[arg(long, value_name = "FILE")]
+    debug_out: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compile a script to a bytecode file
+    Compile {
+        /// Script to compile
+        script: PathBuf,
+
+        /// Output file (defaults to the script path with a .roxc extension)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
 }
 
 fn main() {
     let args = Args::parse();
+    let debug = debug_options(&args);
 
-    if let Some(script) = args.script {
-        run_file(script);
+    if let Some(Command::Compile { script, output }) = args.command {
+        compile_file(script, output);
+    } else if let Some(script) = args.script {
+        if args.dump_ast {
+            dump_ast_file(script);
+        } else {
+            run_file(script, debug);
+        }
     } else {
-        repl();
+        repl(debug);
+    }
+}
+
+fn debug_options(args: &Args) -> DebugOptions {
+    let mut debug = DebugOptions {
+        print_code: args.print_code,
+        // A filter on its own asks for a trace too
+        trace: args.trace || args.trace_filter.is_some(),
+        trace_filter: args.trace_filter.clone(),
+        no_fold: args.no_fold,
+        ..DebugOptions::default()
+    };
+
+    if let Some(path) = &args.debug_out {
+        match fs::File::create(path) {
+            Ok(file) => debug.out = Box::new(file),
+            Err(err) => {
+                eprintln!("Failed to open {}: {err}", path.display());
+                exit(1);
+            }
+        }
     }
+    debug
 }
 
-fn repl() {
+/// File in the home directory that REPL history is kept in between sessions
+const HISTORY_FILE: &str = ".rox_history";
+
+const REPL_HELP: &str = "\
+:help        Show this list
+:quit        Leave the REPL
+:load FILE   Run a script in this session
+:reset       Start over with a fresh VM
+:dis EXPR    Show the bytecode for EXPR without running it
+:type EXPR   Show the type of EXPR's value
+:time EXPR   Run EXPR and show how long it took
+:globals     List global variables
+:gc          Show what's on the heap";
+
+/// Reads and runs one expression at a time on a single VM, so values built on
+/// one line live for the whole session. Every expression's value is echoed
+/// back. Input that stops inside brackets or a string continues on a `...`
+/// prompt. Lines starting with `:` are REPL commands, listed by `:help`.
+fn repl(debug: DebugOptions) {
+    let mut vm = RVM::new();
+    vm.set_debug_options(debug);
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(err) => {
+            eprintln!("Failed to start the REPL: {err}");
+            exit(1);
+        }
+    };
+
+    let history = home::home_dir().map(|dir| dir.join(HISTORY_FILE));
+    if let Some(path) = &history {
+        // There's no history yet on the first run
+        let _ = editor.load_history(path);
+    }
+
+    let mut input = String::new();
     loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
-
-        let mut line = String::new();
-        match io::stdin().read_line(&mut line) {
-            Ok(bytes_read) => {
-                if bytes_read == 0 {
-                    break;
+        let prompt = if input.is_empty() { "> " } else { "... " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if input.is_empty() && line.trim_start().starts_with(':') {
+                    let _ = editor.add_history_entry(line.trim());
+                    if !meta_command(&mut vm, line.trim()) {
+                        break;
+                    }
+                    continue;
                 }
 
-                let _ = interpret(&line);
-                line.clear();
+                input.push_str(&line);
+                input.push('\n');
+                if is_incomplete(&input) {
+                    continue;
+                }
+
+                let source = take(&mut input);
+                if source.trim().is_empty() {
+                    continue;
+                }
+                let _ = editor.add_history_entry(source.trim_end());
+
+                if let Ok(value) = vm.interpret_source(&source) {
+                    println!("{value}");
+                }
             }
+            // Ctrl-C throws away the input so far but keeps the session
+            Err(ReadlineError::Interrupted) => input.clear(),
+            Err(ReadlineError::Eof) => break,
             Err(err) => {
                 eprintln!("Failed to read line: {err}");
+                break;
             }
         }
     }
+
+    if let Some(Err(err)) = history.map(|path| editor.save_history(&path)) {
+        eprintln!("Failed to save history: {err}");
+    }
+}
+
+/// Runs a REPL `:command`. Returns false when the session should end.
+fn meta_command(vm: &mut RVM, line: &str) -> bool {
+    let (command, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let arg = arg.trim();
+
+    match command {
+        ":help" => println!("{REPL_HELP}"),
+        ":quit" => return false,
+        ":load" => match fs::read_to_string(arg) {
+            Ok(source) => {
+                if let Ok(value) = vm.interpret_source(&source) {
+                    println!("{value}");
+                }
+            }
+            Err(err) => eprintln!("Failed to read script: {err}"),
+        },
+        ":reset" => {
+            let debug = vm.take_debug_options();
+            *vm = RVM::new();
+            vm.set_debug_options(debug);
+        }
+        ":dis" => {
+            let mut chunk = Chunk::new();
+            if compile(vm, arg, &mut chunk) {
+                let _ = chunk.disassemble(&mut io::stdout(), arg);
+            }
+        }
+        ":type" => {
+            if let Ok(value) = vm.interpret_source(arg) {
+                println!("{}", value.type_name());
+            }
+        }
+        ":time" => {
+            let start = Instant::now();
+            let result = vm.interpret_source(arg);
+            let elapsed = start.elapsed();
+            if let Ok(value) = result {
+                println!("{value}");
+            }
+            println!("took {elapsed:?}");
+        }
+        ":globals" => println!("The language has no global variables yet"),
+        ":gc" => {
+            for (kind, count) in vm.heap_stats() {
+                println!("{kind:<8}{count}");
+            }
+            println!("Objects are never freed yet, so nothing was collected");
+        }
+        _ => eprintln!("Unknown command {command}, see :help"),
+    }
+    true
 }
 
-fn run_file(path: PathBuf) {
-    match fs::read_to_string(path) {
-        Ok(source) => {
-            let res = interpret(&source);
+fn run_file(path: PathBuf, debug: DebugOptions) {
+    match fs::read(path) {
+        Ok(bytes) => {
+            let res = if is_bytecode(&bytes) {
+                interpret_bytecode(&bytes, debug)
+            } else {
+                match String::from_utf8(bytes) {
+                    Ok(source) => interpret(&source, debug),
+                    Err(err) => {
+                        eprintln!("Failed to read script: {err}");
+                        exit(1);
+                    }
+                }
+            };
 
             if res.is_err() {
                 exit(1);
@@ -72,3 +256,37 @@ fn run_file(path: PathBuf) {
         }
     }
 }
+
+fn compile_file(path: PathBuf, output: Option<PathBuf>) {
+    let source = match fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("Failed to read script: {err}");
+            exit(1);
+        }
+    };
+
+    let Ok(bytes) = compile_to_bytecode(&source) else {
+        exit(1);
+    };
+
+    let output = output.unwrap_or_else(|| path.with_extension("roxc"));
+    if let Err(err) = fs::write(&output, bytes) {
+        eprintln!("Failed to write {}: {err}", output.display());
+        exit(1);
+    }
+}
+
+fn dump_ast_file(path: PathBuf) {
+    let source = match fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("Failed to read script: {err}");
+            exit(1);
+        }
+    };
+
+    if dump_ast(&source).is_err() {
+        exit(1);
+    }
+}
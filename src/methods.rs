@@ -0,0 +1,453 @@
+use crate::object::{ObjKind, ObjList, ObjMap, ObjRange, ObjString};
+use crate::rvm::RVM;
+use crate::value::{Unpacked, Value};
+use std::iter::StepBy;
+use std::ops::Range;
+
+/// Resolves a possibly negative index against a sequence of length `len`.
+/// `allow_end` permits `len` itself, for insertion.
+fn resolve_index(index: &Value, len: usize, allow_end: bool) -> Result<usize, String> {
+    let Some(i) = index.as_int() else {
+        return Err("Index must be an integer".to_string());
+    };
+
+    let resolved = if i < 0 { i + len as i64 } else { i };
+    let limit = if allow_end {
+        len as i64 + 1
+    } else {
+        len as i64
+    };
+    if resolved < 0 || resolved >= limit {
+        Err(format!("Index {} out of range for length {}", i, len))
+    } else {
+        Ok(resolved as usize)
+    }
+}
+
+/// Resolves a slice bound, clamping it into `0..=len` the way out-of-range
+/// slice bounds are forgiven rather than reported.
+fn resolve_bound(bound: &Value, len: usize) -> Result<usize, String> {
+    let Some(i) = bound.as_int() else {
+        return Err("Slice bounds must be integers".to_string());
+    };
+    Ok(clamp_bound(i, 0, len))
+}
+
+fn clamp_bound(bound: i64, extra: i64, len: usize) -> usize {
+    let resolved = if bound < 0 { bound + len as i64 } else { bound };
+    resolved.saturating_add(extra).clamp(0, len as i64) as usize
+}
+
+/// Positions selected by slicing a sequence of length `len` with `range`.
+/// Bounds resolve like `slice()`, and the step must be positive.
+fn range_positions(range: &ObjRange, len: usize) -> Result<StepBy<Range<usize>>, String> {
+    if range.step < 0 {
+        return Err("Slice ranges can't have a negative step".to_string());
+    }
+
+    let start = clamp_bound(range.start, 0, len);
+    let end = clamp_bound(range.end, range.inclusive as i64, len);
+    Ok((start..end.max(start)).step_by(range.step as usize))
+}
+
+fn check_arity(name: &str, args: &[Value], arity: usize) -> Result<(), String> {
+    if args.len() != arity {
+        Err(format!(
+            "{}() expected {} arguments but got {}",
+            name,
+            arity,
+            args.len()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+impl RVM {
+    pub(crate) unsafe fn index_get(
+        &mut self,
+        target: &Value,
+        index: &Value,
+    ) -> Result<Value, String> {
+        let range = index
+            .as_obj_of(ObjKind::Range)
+            .map(|obj| &*(obj as *mut ObjRange));
+
+        match target.unpack() {
+            Unpacked::Obj(obj) if (*obj).kind == ObjKind::List => {
+                let items = &(*(obj as *mut ObjList)).items;
+                if let Some(range) = range {
+                    let slice = range_positions(range, items.len())?
+                        .map(|i| items[i].clone())
+                        .collect();
+                    Ok(Value::obj(self.allocate_obj(ObjList::new(slice))))
+                } else {
+                    let i = resolve_index(index, items.len(), false)?;
+                    Ok(items[i].clone())
+                }
+            }
+            Unpacked::Obj(obj) if (*obj).kind == ObjKind::String => {
+                let chars: Vec<char> = (*(obj as *mut ObjString)).value.chars().collect();
+                let sub = if let Some(range) = range {
+                    range_positions(range, chars.len())?
+                        .map(|i| chars[i])
+                        .collect()
+                } else {
+                    chars[resolve_index(index, chars.len(), false)?].to_string()
+                };
+                Ok(Value::obj(self.allocate_obj(ObjString::new(sub))))
+            }
+            Unpacked::Obj(obj) if (*obj).kind == ObjKind::Map => {
+                match (*(obj as *mut ObjMap)).get(index)? {
+                    Some(value) => Ok(value.clone()),
+                    None => Err(format!("Undefined key '{}'", index)),
+                }
+            }
+            _ => Err("Only lists, strings and maps can be indexed".to_string()),
+        }
+    }
+
+    pub(crate) unsafe fn index_set(
+        &mut self,
+        target: &Value,
+        index: &Value,
+        value: Value,
+    ) -> Result<(), String> {
+        match target.unpack() {
+            Unpacked::Obj(obj) if (*obj).kind == ObjKind::List => {
+                let items = &mut (*(obj as *mut ObjList)).items;
+                let i = resolve_index(index, items.len(), false)?;
+                items[i] = value;
+                Ok(())
+            }
+            Unpacked::Obj(obj) if (*obj).kind == ObjKind::Map => {
+                (*(obj as *mut ObjMap)).insert(index.clone(), value)
+            }
+            _ => Err("Only lists and maps can be assigned by index".to_string()),
+        }
+    }
+
+    pub(crate) unsafe fn invoke(
+        &mut self,
+        receiver: &Value,
+        name: &str,
+        args: &[Value],
+    ) -> Result<Value, String> {
+        match receiver.unpack() {
+            Unpacked::Obj(obj) if (*obj).kind == ObjKind::List => {
+                self.invoke_list(obj as *mut ObjList, name, args)
+            }
+            Unpacked::Obj(obj) if (*obj).kind == ObjKind::Map => {
+                self.invoke_map(obj as *mut ObjMap, name, args)
+            }
+            Unpacked::Obj(obj) if (*obj).kind == ObjKind::Range => {
+                self.invoke_range(obj as *mut ObjRange, name, args)
+            }
+            _ => Err(format!("Undefined method '{}'", name)),
+        }
+    }
+
+    unsafe fn invoke_list(
+        &mut self,
+        list: *mut ObjList,
+        name: &str,
+        args: &[Value],
+    ) -> Result<Value, String> {
+        let items = &mut (*list).items;
+        match name {
+            "push" => {
+                check_arity(name, args, 1)?;
+                items.push(args[0].clone());
+                Ok(Value::nil())
+            }
+            "pop" => {
+                check_arity(name, args, 0)?;
+                items
+                    .pop()
+                    .ok_or_else(|| "Can't pop from an empty list".to_string())
+            }
+            "insert" => {
+                check_arity(name, args, 2)?;
+                let i = resolve_index(&args[0], items.len(), true)?;
+                items.insert(i, args[1].clone());
+                Ok(Value::nil())
+            }
+            "remove" => {
+                check_arity(name, args, 1)?;
+                let i = resolve_index(&args[0], items.len(), false)?;
+                Ok(items.remove(i))
+            }
+            "len" => {
+                check_arity(name, args, 0)?;
+                Ok(self.int(items.len() as i64))
+            }
+            "slice" => {
+                if args.is_empty() || args.len() > 2 {
+                    return Err(format!(
+                        "slice() expected 1 or 2 arguments but got {}",
+                        args.len()
+                    ));
+                }
+                let start = resolve_bound(&args[0], items.len())?;
+                let end = match args.get(1) {
+                    Some(end) => resolve_bound(end, items.len())?,
+                    None => items.len(),
+                };
+                let slice = items[start..end.max(start)].to_vec();
+                Ok(Value::obj(self.allocate_obj(ObjList::new(slice))))
+            }
+            "sort" => {
+                check_arity(name, args, 0)?;
+                let all_numbers = items
+                    .iter()
+                    .all(|v| v.as_f64().is_some_and(|n| !n.is_nan()));
+                let all_strings = items.iter().all(|v| v.as_obj_of(ObjKind::String).is_some());
+                if !all_numbers && !all_strings {
+                    return Err("Can only sort lists of numbers or lists of strings".to_string());
+                }
+                items.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                Ok(Value::nil())
+            }
+            _ => Err(format!("Undefined method '{}' on list", name)),
+        }
+    }
+
+    unsafe fn invoke_map(
+        &mut self,
+        map: *mut ObjMap,
+        name: &str,
+        args: &[Value],
+    ) -> Result<Value, String> {
+        let map = &mut *map;
+        match name {
+            "keys" => {
+                check_arity(name, args, 0)?;
+                let keys = map.entries.iter().map(|(k, _)| k.clone()).collect();
+                Ok(Value::obj(self.allocate_obj(ObjList::new(keys))))
+            }
+            "values" => {
+                check_arity(name, args, 0)?;
+                let values = map.entries.iter().map(|(_, v)| v.clone()).collect();
+                Ok(Value::obj(self.allocate_obj(ObjList::new(values))))
+            }
+            "has" => {
+                check_arity(name, args, 1)?;
+                Ok(Value::boolean(map.get(&args[0])?.is_some()))
+            }
+            "remove" => {
+                check_arity(name, args, 1)?;
+                map.remove(&args[0])?
+                    .ok_or_else(|| format!("Undefined key '{}'", args[0]))
+            }
+            "len" => {
+                check_arity(name, args, 0)?;
+                Ok(self.int(map.entries.len() as i64))
+            }
+            _ => Err(format!("Undefined method '{}' on map", name)),
+        }
+    }
+
+    unsafe fn invoke_range(
+        &mut self,
+        range: *mut ObjRange,
+        name: &str,
+        args: &[Value],
+    ) -> Result<Value, String> {
+        let range = &*range;
+        match name {
+            "step" => {
+                check_arity(name, args, 1)?;
+                match args[0].as_int() {
+                    Some(0) => Err("Range step can't be zero".to_string()),
+                    Some(step) => {
+                        let stepped = ObjRange::new(range.start, range.end, range.inclusive, step);
+                        Ok(Value::obj(self.allocate_obj(stepped)))
+                    }
+                    None => Err("Range step must be an integer".to_string()),
+                }
+            }
+            "contains" => {
+                check_arity(name, args, 1)?;
+                Ok(Value::boolean(
+                    args[0].as_int().is_some_and(|n| range.contains(n)),
+                ))
+            }
+            "len" => {
+                check_arity(name, args, 0)?;
+                i64::try_from(range.len())
+                    .map(|n| self.int(n))
+                    .map_err(|_| "Range is too long to measure".to_string())
+            }
+            _ => Err(format!("Undefined method '{}' on range", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(vm: &mut RVM, source: &str) -> Value {
+        match vm.interpret_source(source) {
+            Ok(value) => value,
+            Err(_) => panic!("{} did not run", source),
+        }
+    }
+
+    /// Calls `name` on `receiver` with each of `args` evaluated, and returns
+    /// the result as printed or the error message.
+    fn invoke(vm: &mut RVM, receiver: &Value, name: &str, args: &[&str]) -> Result<String, String> {
+        let args: Vec<Value> = args.iter().map(|arg| eval(vm, arg)).collect();
+        unsafe { vm.invoke(receiver, name, &args) }.map(|v| v.to_string())
+    }
+
+    fn index(vm: &mut RVM, target: &str, index: &str) -> Result<String, String> {
+        let target = eval(vm, target);
+        let index = eval(vm, index);
+        unsafe { vm.index_get(&target, &index) }.map(|v| v.to_string())
+    }
+
+    #[test]
+    fn ranges_step_contain_and_measure() {
+        let mut vm = RVM::new();
+        let call = |vm: &mut RVM, range: &str, name: &str, args: &[&str]| {
+            let range = eval(vm, range);
+            invoke(vm, &range, name, args)
+        };
+
+        assert_eq!(
+            call(&mut vm, "0 .. 10", "step", &["3"]).unwrap(),
+            "(0..10).step(3)"
+        );
+        assert_eq!(
+            call(&mut vm, "0 .. 10", "step", &["0"]).unwrap_err(),
+            "Range step can't be zero"
+        );
+
+        for (range, len, inside, outside) in [
+            ("0 .. 10", "10", Some("9"), "10"),
+            ("0 ..= 10", "11", Some("10"), "11"),
+            ("(0 .. 10).step(3)", "4", Some("9"), "8"),
+            ("(0 ..= 9).step(3)", "4", Some("9"), "10"),
+            ("(10 .. 0).step(-3)", "4", Some("1"), "0"),
+            ("(10 ..= 1).step(-3)", "4", Some("1"), "13"),
+            ("5 .. 1", "0", None, "3"),
+        ] {
+            assert_eq!(call(&mut vm, range, "len", &[]).unwrap(), len, "{range}");
+            let contains = |vm: &mut RVM, n| call(vm, range, "contains", &[n]).unwrap();
+            if let Some(inside) = inside {
+                assert_eq!(contains(&mut vm, inside), "true", "{range}");
+            }
+            assert_eq!(contains(&mut vm, outside), "false", "{range}");
+        }
+
+        assert_eq!(
+            call(
+                &mut vm,
+                "(-9223372036854775807 - 1) ..= 9223372036854775807",
+                "len",
+                &[]
+            )
+            .unwrap_err(),
+            "Range is too long to measure"
+        );
+    }
+
+    #[test]
+    fn ranges_slice_lists_and_strings() {
+        let mut vm = RVM::new();
+        for (target, range, expected) in [
+            ("[0, 1, 2, 3, 4]", "-3 .. -1", "[2, 3]"),
+            ("[0, 1, 2, 3, 4]", "-2 ..= -1", "[3, 4]"),
+            ("[0, 1, 2, 3, 4]", "(0 .. 5).step(2)", "[0, 2, 4]"),
+            ("[0, 1, 2, 3, 4]", "-100 .. 100", "[0, 1, 2, 3, 4]"),
+            ("[0, 1, 2, 3, 4]", "3 .. 1", "[]"),
+            ("\"hello\"", "-4 .. -1", "ell"),
+            ("\"hello\"", "(0 ..= -1).step(2)", "hlo"),
+            ("\"hello\"", "-1 ..= 10", "o"),
+        ] {
+            assert_eq!(
+                index(&mut vm, target, range).unwrap(),
+                expected,
+                "{target}[{range}]"
+            );
+        }
+
+        assert_eq!(
+            index(&mut vm, "[0, 1, 2]", "(2 .. 0).step(-1)").unwrap_err(),
+            "Slice ranges can't have a negative step"
+        );
+    }
+
+    #[test]
+    fn lists_resolve_negative_indices() {
+        let mut vm = RVM::new();
+        assert_eq!(index(&mut vm, "[1, 2, 3]", "-1").unwrap(), "3");
+        assert_eq!(index(&mut vm, "[1, 2, 3]", "-3").unwrap(), "1");
+        assert_eq!(
+            index(&mut vm, "[1, 2, 3]", "-4").unwrap_err(),
+            "Index -4 out of range for length 3"
+        );
+        assert_eq!(
+            resolve_index(&Value::int(3), 3, false).unwrap_err(),
+            "Index 3 out of range for length 3"
+        );
+        assert_eq!(resolve_index(&Value::int(3), 3, true).unwrap(), 3);
+    }
+
+    #[test]
+    fn list_methods() {
+        let mut vm = RVM::new();
+        let list = eval(&mut vm, "[1, 2]");
+        invoke(&mut vm, &list, "insert", &["2", "4"]).unwrap();
+        invoke(&mut vm, &list, "insert", &["-1", "3"]).unwrap();
+        assert_eq!(list.to_string(), "[1, 2, 3, 4]");
+        assert_eq!(
+            invoke(&mut vm, &list, "insert", &["5", "5"]).unwrap_err(),
+            "Index 5 out of range for length 4"
+        );
+
+        for (args, expected) in [
+            (&["-100", "100"][..], "[1, 2, 3, 4]"),
+            (&["-2"], "[3, 4]"),
+            (&["3", "1"], "[]"),
+            (&["1", "-1"], "[2, 3]"),
+        ] {
+            assert_eq!(invoke(&mut vm, &list, "slice", args).unwrap(), expected);
+        }
+
+        let list = eval(&mut vm, "[3, 1.5, 2]");
+        invoke(&mut vm, &list, "sort", &[]).unwrap();
+        assert_eq!(list.to_string(), "[1.5, 2, 3]");
+        for unsortable in ["[1, \"a\"]", "[1, nil]", "[1, 0.0 / 0.0]"] {
+            let list = eval(&mut vm, unsortable);
+            assert_eq!(
+                invoke(&mut vm, &list, "sort", &[]).unwrap_err(),
+                "Can only sort lists of numbers or lists of strings"
+            );
+        }
+    }
+
+    #[test]
+    fn map_methods() {
+        let mut vm = RVM::new();
+        let map = eval(&mut vm, "{\"a\": 1, 2: \"b\", 3.0: nil}");
+        assert_eq!(invoke(&mut vm, &map, "remove", &["\"a\""]).unwrap(), "1");
+        assert_eq!(
+            invoke(&mut vm, &map, "remove", &["\"a\""]).unwrap_err(),
+            "Undefined key 'a'"
+        );
+        assert_eq!(invoke(&mut vm, &map, "keys", &[]).unwrap(), "[2, 3]");
+        assert_eq!(
+            invoke(&mut vm, &map, "values", &[]).unwrap(),
+            "[\"b\", nil]"
+        );
+        assert_eq!(invoke(&mut vm, &map, "has", &["3"]).unwrap(), "true");
+        assert_eq!(invoke(&mut vm, &map, "has", &["2.0"]).unwrap(), "true");
+        assert_eq!(
+            invoke(&mut vm, &map, "has", &["0.0 / 0.0"]).unwrap_err(),
+            "Map key can't be NaN"
+        );
+        assert_eq!(invoke(&mut vm, &map, "len", &[]).unwrap(), "2");
+    }
+}
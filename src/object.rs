@@ -1,8 +1,26 @@
+use crate::value::{Unpacked, Value};
+use hashbrown::HashMap;
 use std::ptr;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum ObjKind {
     String,
+    List,
+    Map,
+    Range,
+    Int,
+}
+
+impl ObjKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjKind::String => "string",
+            ObjKind::List => "list",
+            ObjKind::Map => "map",
+            ObjKind::Range => "range",
+            ObjKind::Int => "int",
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -30,3 +48,284 @@ impl ObjString {
         })
     }
 }
+
+/// An int too wide for a NaN-boxed value's payload. Only `nan-boxing` builds
+/// allocate these, and the value that points at one is tagged as an int, so
+/// it unpacks as `Unpacked::Int` rather than as an object.
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct ObjInt {
+    pub obj: Obj,
+    pub value: i64,
+}
+
+impl ObjInt {
+    pub fn new(value: i64) -> Box<Self> {
+        Box::new(Self {
+            obj: Obj {
+                kind: ObjKind::Int,
+                next: ptr::null_mut(),
+            },
+            value,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct ObjList {
+    pub obj: Obj,
+    pub items: Vec<Value>,
+}
+
+impl ObjList {
+    pub fn new(items: Vec<Value>) -> Box<Self> {
+        Box::new(Self {
+            obj: Obj {
+                kind: ObjKind::List,
+                next: ptr::null_mut(),
+            },
+            items,
+        })
+    }
+}
+
+/// Hashable identity of a map key. Strings and ranges hash by contents, other
+/// objects by address, and integral floats as ints so that `1` and `1.0` are
+/// the same key.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Nil,
+    Boolean(bool),
+    Int(i64),
+    Number(u64),
+    String(String),
+    Range(i64, i64, bool, i64),
+    Obj(*mut Obj),
+}
+
+impl MapKey {
+    pub unsafe fn from_value(value: &Value) -> Result<Self, String> {
+        match value.unpack() {
+            Unpacked::Nil => Ok(MapKey::Nil),
+            Unpacked::Boolean(b) => Ok(MapKey::Boolean(b)),
+            Unpacked::Int(n) => Ok(MapKey::Int(n)),
+            Unpacked::Number(n) if n.is_nan() => Err("Map key can't be NaN".to_string()),
+            Unpacked::Number(n) => {
+                if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+                    Ok(MapKey::Int(n as i64))
+                } else {
+                    Ok(MapKey::Number(n.to_bits()))
+                }
+            }
+            Unpacked::Obj(obj) => match (*obj).kind {
+                ObjKind::String => Ok(MapKey::String((*(obj as *mut ObjString)).value.clone())),
+                ObjKind::Range => {
+                    let r = &*(obj as *mut ObjRange);
+                    Ok(MapKey::Range(r.start, r.end, r.inclusive, r.step))
+                }
+                _ => Ok(MapKey::Obj(obj)),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct ObjMap {
+    pub obj: Obj,
+    pub entries: Vec<(Value, Value)>,
+    pub indices: HashMap<MapKey, usize>,
+}
+
+impl ObjMap {
+    pub fn new() -> Box<Self> {
+        Box::new(Self {
+            obj: Obj {
+                kind: ObjKind::Map,
+                next: ptr::null_mut(),
+            },
+            entries: vec![],
+            indices: HashMap::new(),
+        })
+    }
+
+    pub unsafe fn get(&self, key: &Value) -> Result<Option<&Value>, String> {
+        let key = MapKey::from_value(key)?;
+        Ok(self.indices.get(&key).map(|&i| &self.entries[i].1))
+    }
+
+    /// Inserts or overwrites `key`. A new key goes to the end of the
+    /// iteration order; an existing key keeps its position.
+    pub unsafe fn insert(&mut self, key: Value, value: Value) -> Result<(), String> {
+        let map_key = MapKey::from_value(&key)?;
+        match self.indices.get(&map_key) {
+            Some(&i) => self.entries[i].1 = value,
+            None => {
+                self.indices.insert(map_key, self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+        Ok(())
+    }
+
+    pub unsafe fn remove(&mut self, key: &Value) -> Result<Option<Value>, String> {
+        let map_key = MapKey::from_value(key)?;
+        let Some(i) = self.indices.remove(&map_key) else {
+            return Ok(None);
+        };
+
+        // Shift later entries down so iteration order is preserved
+        for idx in self.indices.values_mut() {
+            if *idx > i {
+                *idx -= 1;
+            }
+        }
+        Ok(Some(self.entries.remove(i).1))
+    }
+}
+
+/// An integer range, `start..end` or `start..=end`, walked in `step`s.
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct ObjRange {
+    pub obj: Obj,
+    pub start: i64,
+    pub end: i64,
+    pub inclusive: bool,
+    pub step: i64,
+}
+
+impl ObjRange {
+    pub fn new(start: i64, end: i64, inclusive: bool, step: i64) -> Box<Self> {
+        Box::new(Self {
+            obj: Obj {
+                kind: ObjKind::Range,
+                next: ptr::null_mut(),
+            },
+            start,
+            end,
+            inclusive,
+            step,
+        })
+    }
+
+    pub fn len(&self) -> u128 {
+        // i128 keeps 'i64::MIN..=i64::MAX', 2^64 long, from overflowing
+        let (start, end, step) = (self.start as i128, self.end as i128, self.step as i128);
+        let span = match (step > 0, self.inclusive) {
+            (true, false) => end - start,
+            (true, true) => end - start + 1,
+            (false, false) => start - end,
+            (false, true) => start - end + 1,
+        };
+        if span <= 0 {
+            0
+        } else {
+            ((span + step.abs() - 1) / step.abs()) as u128
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, n: i64) -> bool {
+        let offset = n as i128 - self.start as i128;
+        if offset % self.step as i128 != 0 {
+            return false;
+        }
+        let steps = offset / self.step as i128;
+        steps >= 0 && (steps as u128) < self.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rvm::RVM;
+
+    fn keys(map: &ObjMap) -> Vec<String> {
+        map.entries.iter().map(|(key, _)| key.to_string()).collect()
+    }
+
+    fn get(map: &ObjMap, key: Value) -> Option<i64> {
+        unsafe { map.get(&key).unwrap().and_then(Value::as_int) }
+    }
+
+    #[test]
+    fn maps_keep_insertion_order_after_remove() {
+        let mut map = ObjMap::new();
+        unsafe {
+            for n in 0..4 {
+                map.insert(Value::int(n), Value::int(n * 10)).unwrap();
+            }
+            assert_eq!(
+                map.remove(&Value::int(1)).unwrap().and_then(|v| v.as_int()),
+                Some(10)
+            );
+            assert!(map.remove(&Value::int(1)).unwrap().is_none());
+            map.insert(Value::int(0), Value::int(-1)).unwrap();
+            map.insert(Value::int(1), Value::int(11)).unwrap();
+        }
+        assert_eq!(keys(&map), ["0", "2", "3", "1"]);
+        assert_eq!(get(&map, Value::int(0)), Some(-1));
+        assert_eq!(get(&map, Value::int(3)), Some(30));
+        assert_eq!(get(&map, Value::int(1)), Some(11));
+    }
+
+    #[test]
+    fn integral_floats_are_int_keys() {
+        let mut map = ObjMap::new();
+        unsafe {
+            map.insert(Value::int(1), Value::int(1)).unwrap();
+            map.insert(Value::number(1.0), Value::int(2)).unwrap();
+            map.insert(Value::number(1.5), Value::int(3)).unwrap();
+            map.insert(Value::number(-0.0), Value::int(4)).unwrap();
+        }
+        assert_eq!(keys(&map), ["1", "1.5", "-0"]);
+        assert_eq!(get(&map, Value::int(1)), Some(2));
+        assert_eq!(get(&map, Value::number(1.0)), Some(2));
+        assert_eq!(get(&map, Value::int(0)), Some(4));
+    }
+
+    #[test]
+    fn nan_keys_are_rejected() {
+        let mut map = ObjMap::new();
+        let nan = Value::number(f64::NAN);
+        unsafe {
+            assert_eq!(
+                map.insert(nan.clone(), Value::nil()).unwrap_err(),
+                "Map key can't be NaN"
+            );
+            assert!(map.get(&nan).is_err());
+            assert!(map.remove(&nan).is_err());
+        }
+        assert!(map.entries.is_empty());
+    }
+
+    #[test]
+    fn lists_are_keyed_by_identity() {
+        let mut vm = RVM::new();
+        let mut map = ObjMap::new();
+        unsafe {
+            let a = Value::obj(vm.allocate_obj(ObjList::new(vec![Value::int(1)])));
+            let b = Value::obj(vm.allocate_obj(ObjList::new(vec![Value::int(1)])));
+            map.insert(a.clone(), Value::int(1)).unwrap();
+            map.insert(b.clone(), Value::int(2)).unwrap();
+            assert_eq!(get(&map, a.clone()), Some(1));
+            assert_eq!(get(&map, b), Some(2));
+            assert_eq!(
+                MapKey::from_value(&a).unwrap(),
+                MapKey::Obj(a.as_obj().unwrap())
+            );
+
+            let s = Value::obj(vm.allocate_obj(ObjString::new("k".to_string())));
+            let t = Value::obj(vm.allocate_obj(ObjString::new("k".to_string())));
+            map.insert(s, Value::int(3)).unwrap();
+            map.insert(t.clone(), Value::int(4)).unwrap();
+            assert_eq!(get(&map, t), Some(4));
+        }
+        assert_eq!(map.entries.len(), 3);
+    }
+}
@@ -0,0 +1,272 @@
+use crate::object::{ObjKind, ObjString};
+use crate::rvm::RVM;
+use crate::value::Value;
+use num::Integer;
+use std::cmp::Ordering;
+
+/// The runtime errors an operator can raise.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OpError {
+    NotANumber,
+    NotAnInteger,
+    NotNumbers,
+    NotNumbersOrStrings,
+    NotIntegers,
+    Overflow,
+    DivisionByZero,
+    ShiftOutOfRange,
+}
+
+impl OpError {
+    pub fn message(self) -> &'static str {
+        match self {
+            OpError::NotANumber => "Operand must be a number",
+            OpError::NotAnInteger => "Operand must be an integer",
+            OpError::NotNumbers => "Operands must be numbers",
+            OpError::NotNumbersOrStrings => "Operands must be numbers or strings",
+            OpError::NotIntegers => "Operands must be integers",
+            OpError::Overflow => "Integer overflow",
+            OpError::DivisionByZero => "Integer division by zero",
+            OpError::ShiftOutOfRange => "Shift amount must be between 0 and 63",
+        }
+    }
+}
+
+/// An operator's result, or the runtime error it raises.
+pub type OpResult = Result<Value, OpError>;
+
+pub type UnaryOp = fn(&mut RVM, &Value) -> OpResult;
+pub type BinaryOp = fn(&mut RVM, &Value, &Value) -> OpResult;
+
+// What each operator computes. `RVM::run` reports an `Err` as a runtime
+// error; constant folding leaves the expression in the code instead, so the
+// same error is still raised at runtime with its line.
+impl RVM {
+    #[inline(always)]
+    pub(crate) fn negate(&mut self, v: &Value) -> OpResult {
+        if let Some(v) = v.as_int() {
+            v.checked_neg()
+                .map(|v| self.int(v))
+                .ok_or(OpError::Overflow)
+        } else if let Some(v) = v.as_number() {
+            Ok(Value::number(-v))
+        } else {
+            Err(OpError::NotANumber)
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn bit_not(&mut self, v: &Value) -> OpResult {
+        match v.as_int() {
+            Some(v) => Ok(self.int(!v)),
+            None => Err(OpError::NotAnInteger),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn add(&mut self, a: &Value, b: &Value) -> OpResult {
+        if a.as_f64().is_some() && b.as_f64().is_some() {
+            self.arithmetic(a, b, i64::checked_add, |a, b| a + b)
+        } else if let Some(s) = unsafe { concat_strings(a, b) } {
+            // Values only ever point at live objects
+            Ok(Value::obj(unsafe { self.allocate_obj(ObjString::new(s)) }))
+        } else {
+            Err(OpError::NotNumbersOrStrings)
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn subtract(&mut self, a: &Value, b: &Value) -> OpResult {
+        self.arithmetic(a, b, i64::checked_sub, |a, b| a - b)
+    }
+
+    #[inline(always)]
+    pub(crate) fn multiply(&mut self, a: &Value, b: &Value) -> OpResult {
+        self.arithmetic(a, b, i64::checked_mul, |a, b| a * b)
+    }
+
+    #[inline(always)]
+    pub(crate) fn divide(&mut self, a: &Value, b: &Value) -> OpResult {
+        match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) => Ok(Value::number(a / b)),
+            _ => Err(OpError::NotNumbers),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn floor_divide(&mut self, a: &Value, b: &Value) -> OpResult {
+        self.floor(a, b, Integer::div_floor, None, |a, b| (a / b).floor())
+    }
+
+    #[inline(always)]
+    pub(crate) fn modulo(&mut self, a: &Value, b: &Value) -> OpResult {
+        self.floor(a, b, Integer::mod_floor, Some(0), |a, b| {
+            a - b * (a / b).floor()
+        })
+    }
+
+    #[inline(always)]
+    pub(crate) fn power(&mut self, a: &Value, b: &Value) -> OpResult {
+        if let Some(b) = b.as_int()
+            && let Some(a) = a.as_int()
+            && b >= 0
+        {
+            // Huge exponents only succeed for bases 0, 1 and -1, so clamping
+            // while keeping the exponent's parity gives the same result
+            let exp = u32::try_from(b).unwrap_or(u32::MAX - (b & 1 == 0) as u32);
+            a.checked_pow(exp)
+                .map(|c| self.int(c))
+                .ok_or(OpError::Overflow)
+        } else if let Some(b) = b.as_f64()
+            && let Some(a) = a.as_f64()
+        {
+            Ok(Value::number(a.powf(b)))
+        } else {
+            Err(OpError::NotNumbers)
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn greater(&mut self, a: &Value, b: &Value) -> OpResult {
+        compare(a, b, |o| o == Some(Ordering::Greater))
+    }
+
+    // '>=' and '<=' are the negation of the opposite comparison, so they're
+    // true rather than false when a NaN is involved
+    #[inline(always)]
+    pub(crate) fn greater_equal(&mut self, a: &Value, b: &Value) -> OpResult {
+        compare(a, b, |o| o != Some(Ordering::Less))
+    }
+
+    #[inline(always)]
+    pub(crate) fn less(&mut self, a: &Value, b: &Value) -> OpResult {
+        compare(a, b, |o| o == Some(Ordering::Less))
+    }
+
+    #[inline(always)]
+    pub(crate) fn less_equal(&mut self, a: &Value, b: &Value) -> OpResult {
+        compare(a, b, |o| o != Some(Ordering::Greater))
+    }
+
+    #[inline(always)]
+    pub(crate) fn bit_and(&mut self, a: &Value, b: &Value) -> OpResult {
+        self.bitwise(a, b, |a, b| a & b)
+    }
+
+    #[inline(always)]
+    pub(crate) fn bit_or(&mut self, a: &Value, b: &Value) -> OpResult {
+        self.bitwise(a, b, |a, b| a | b)
+    }
+
+    #[inline(always)]
+    pub(crate) fn bit_xor(&mut self, a: &Value, b: &Value) -> OpResult {
+        self.bitwise(a, b, |a, b| a ^ b)
+    }
+
+    #[inline(always)]
+    pub(crate) fn shift_left(&mut self, a: &Value, b: &Value) -> OpResult {
+        self.shift(a, b, i64::checked_shl)
+    }
+
+    #[inline(always)]
+    pub(crate) fn shift_right(&mut self, a: &Value, b: &Value) -> OpResult {
+        self.shift(a, b, i64::checked_shr)
+    }
+
+    /// Ints stay ints, with overflow reported; anything involving a float
+    /// is computed as floats.
+    #[inline(always)]
+    fn arithmetic(
+        &mut self,
+        a: &Value,
+        b: &Value,
+        int_fn: fn(i64, i64) -> Option<i64>,
+        float_fn: fn(f64, f64) -> f64,
+    ) -> OpResult {
+        if let Some(b) = b.as_int()
+            && let Some(a) = a.as_int()
+        {
+            int_fn(a, b).map(|c| self.int(c)).ok_or(OpError::Overflow)
+        } else if let Some(b) = b.as_f64()
+            && let Some(a) = a.as_f64()
+        {
+            Ok(Value::number(float_fn(a, b)))
+        } else {
+            Err(OpError::NotNumbers)
+        }
+    }
+
+    /// `min_by_minus_one` is the result for `i64::MIN` and -1, which
+    /// `Integer` can't compute; `None` reports an overflow.
+    #[inline(always)]
+    fn floor(
+        &mut self,
+        a: &Value,
+        b: &Value,
+        int_fn: fn(&i64, &i64) -> i64,
+        min_by_minus_one: Option<i64>,
+        float_fn: fn(f64, f64) -> f64,
+    ) -> OpResult {
+        if let Some(b) = b.as_int()
+            && let Some(a) = a.as_int()
+        {
+            if b == 0 {
+                Err(OpError::DivisionByZero)
+            } else if a == i64::MIN && b == -1 {
+                min_by_minus_one
+                    .map(|c| self.int(c))
+                    .ok_or(OpError::Overflow)
+            } else {
+                Ok(self.int(int_fn(&a, &b)))
+            }
+        } else if let Some(b) = b.as_f64()
+            && let Some(a) = a.as_f64()
+        {
+            Ok(Value::number(float_fn(a, b)))
+        } else {
+            Err(OpError::NotNumbers)
+        }
+    }
+
+    #[inline(always)]
+    fn bitwise(&mut self, a: &Value, b: &Value, int_fn: fn(i64, i64) -> i64) -> OpResult {
+        match (a.as_int(), b.as_int()) {
+            (Some(a), Some(b)) => Ok(self.int(int_fn(a, b))),
+            _ => Err(OpError::NotIntegers),
+        }
+    }
+
+    #[inline(always)]
+    fn shift(&mut self, a: &Value, b: &Value, shift_fn: fn(i64, u32) -> Option<i64>) -> OpResult {
+        let (Some(a), Some(b)) = (a.as_int(), b.as_int()) else {
+            return Err(OpError::NotIntegers);
+        };
+        u32::try_from(b)
+            .ok()
+            .and_then(|b| shift_fn(a, b))
+            .map(|c| self.int(c))
+            .ok_or(OpError::ShiftOutOfRange)
+    }
+}
+
+/// The two strings joined, or `None` unless both values are strings.
+pub unsafe fn concat_strings(a: &Value, b: &Value) -> Option<String> {
+    let a = a.as_obj_of(ObjKind::String)? as *mut ObjString;
+    let b = b.as_obj_of(ObjKind::String)? as *mut ObjString;
+    Some((*a).value.clone() + &(*b).value)
+}
+
+/// Orders two numbers, comparing an int with a float exactly rather than
+/// rounding the int to the nearest float first.
+#[inline(always)]
+fn compare(a: &Value, b: &Value, test: fn(Option<Ordering>) -> bool) -> OpResult {
+    if let Some(b) = b.as_int()
+        && let Some(a) = a.as_int()
+    {
+        Ok(Value::boolean(test(a.partial_cmp(&b))))
+    } else if a.as_f64().is_some() && b.as_f64().is_some() {
+        Ok(Value::boolean(test(a.partial_cmp(b))))
+    } else {
+        Err(OpError::NotNumbers)
+    }
+}
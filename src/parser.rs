@@ -0,0 +1,355 @@
+use crate::ast::{Expr, ExprKind, Ident, Literal, Script, Span};
+use crate::compiler::{rhs_precedence, rules, ParseRule, Precedence, Rules, Tokens};
+use crate::scanner::{Scanner, TokenKind};
+use hashbrown::HashMap;
+
+/// Parses `source` into a tree. Reports errors as the single-pass compiler
+/// does, in the same order, and returns `None` if there were any.
+pub fn parse(source: &str) -> Option<Script<'_>> {
+    let mut scanner = Scanner::new(source);
+    let mut parser = Parser::new(source);
+
+    parser.tokens.advance(&mut scanner);
+    let body = parser.expression(&mut scanner);
+    parser
+        .tokens
+        .consume(&mut scanner, TokenKind::Eof, "Expect end of expression");
+    let eof = parser.prev_span();
+
+    (!parser.tokens.had_error).then_some(Script { body, eof })
+}
+
+type PrefixFn<'a> = fn(&mut Parser<'a>, &mut Scanner<'a>, bool) -> Expr<'a>;
+type InfixFn<'a> = fn(&mut Parser<'a>, &mut Scanner<'a>, Expr<'a>, bool) -> Expr<'a>;
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Tokens<'a>,
+    rules: HashMap<TokenKind, ParseRule<PrefixFn<'a>, InfixFn<'a>>>,
+}
+
+impl<'a> Rules for Parser<'a> {
+    type Prefix = PrefixFn<'a>;
+    type Infix = InfixFn<'a>;
+
+    const GROUPING: PrefixFn<'a> = Self::grouping;
+    const LIST: PrefixFn<'a> = Self::list;
+    const MAP: PrefixFn<'a> = Self::map;
+    const UNARY: PrefixFn<'a> = Self::unary;
+    const NUMBER: PrefixFn<'a> = Self::number;
+    const STRING: PrefixFn<'a> = Self::string;
+    const LITERAL: PrefixFn<'a> = Self::literal;
+    const BINARY: InfixFn<'a> = Self::binary;
+    const SUBSCRIPT: InfixFn<'a> = Self::subscript;
+    const DOT: InfixFn<'a> = Self::dot;
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            tokens: Tokens::default(),
+            rules: rules::<Self>(),
+        }
+    }
+
+    fn prev_span(&self) -> Span {
+        // Tokens made by the scanner borrow their lexeme from the source
+        let start = (self.tokens.prev.lexeme.as_ptr() as usize)
+            .saturating_sub(self.source.as_ptr() as usize);
+        Span {
+            start,
+            end: start + self.tokens.prev.lexeme.len(),
+            line: self.tokens.prev.line,
+            end_line: self.tokens.prev.line,
+        }
+    }
+
+    /// A node covering everything from `start` up to the previous token.
+    fn node(&self, start: Span, kind: ExprKind<'a>) -> Expr<'a> {
+        Expr {
+            kind,
+            span: start.to(self.prev_span()),
+        }
+    }
+
+    fn binary(&mut self, scanner: &mut Scanner<'a>, lhs: Expr<'a>, _can_assign: bool) -> Expr<'a> {
+        let op = self.tokens.prev.kind;
+        let precedence = rhs_precedence(op, self.rules[&op].precedence);
+        let rhs = self.parse_precedence(scanner, precedence);
+
+        let start = lhs.span;
+        self.node(
+            start,
+            ExprKind::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            },
+        )
+    }
+
+    fn dot(
+        &mut self,
+        scanner: &mut Scanner<'a>,
+        receiver: Expr<'a>,
+        _can_assign: bool,
+    ) -> Expr<'a> {
+        self.tokens.consume(
+            scanner,
+            TokenKind::Identifier,
+            "Expect method name after '.'",
+        );
+        let name = Ident {
+            name: self.tokens.prev.lexeme,
+            span: self.prev_span(),
+        };
+
+        self.tokens.consume(
+            scanner,
+            TokenKind::LeftParen,
+            "Expect '(' after method name",
+        );
+        let args = self.argument_list(scanner);
+
+        let start = receiver.span;
+        self.node(
+            start,
+            ExprKind::Invoke {
+                receiver: Box::new(receiver),
+                name,
+                args,
+            },
+        )
+    }
+
+    fn argument_list(&mut self, scanner: &mut Scanner<'a>) -> Vec<Expr<'a>> {
+        let mut args = vec![];
+        if !self.tokens.check(TokenKind::RightParen) {
+            loop {
+                args.push(self.expression(scanner));
+                if args.len() > u8::MAX as usize {
+                    self.tokens.error("Can't have more than 255 arguments");
+                }
+
+                if !self.tokens.match_token(scanner, TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.tokens
+            .consume(scanner, TokenKind::RightParen, "Expect ')' after arguments");
+        args
+    }
+
+    fn list(&mut self, scanner: &mut Scanner<'a>, _can_assign: bool) -> Expr<'a> {
+        let start = self.prev_span();
+        let mut items = vec![];
+        if !self.tokens.check(TokenKind::RightBracket) {
+            loop {
+                // Allow a trailing comma
+                if self.tokens.check(TokenKind::RightBracket) {
+                    break;
+                }
+
+                items.push(self.expression(scanner));
+                if items.len() > u8::MAX as usize {
+                    self.tokens
+                        .error("Can't have more than 255 elements in a list literal");
+                }
+
+                if !self.tokens.match_token(scanner, TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.tokens.consume(
+            scanner,
+            TokenKind::RightBracket,
+            "Expect ']' after list elements",
+        );
+        self.node(start, ExprKind::List(items))
+    }
+
+    fn map(&mut self, scanner: &mut Scanner<'a>, _can_assign: bool) -> Expr<'a> {
+        let start = self.prev_span();
+        let mut entries = vec![];
+        if !self.tokens.check(TokenKind::RightBrace) {
+            loop {
+                // Allow a trailing comma
+                if self.tokens.check(TokenKind::RightBrace) {
+                    break;
+                }
+
+                let key = self.expression(scanner);
+                self.tokens
+                    .consume(scanner, TokenKind::Colon, "Expect ':' after map key");
+                let value = self.expression(scanner);
+                entries.push((key, value));
+                if entries.len() > u8::MAX as usize {
+                    self.tokens
+                        .error("Can't have more than 255 entries in a map literal");
+                }
+
+                if !self.tokens.match_token(scanner, TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.tokens.consume(
+            scanner,
+            TokenKind::RightBrace,
+            "Expect '}' after map entries",
+        );
+        self.node(start, ExprKind::Map(entries))
+    }
+
+    fn subscript(
+        &mut self,
+        scanner: &mut Scanner<'a>,
+        target: Expr<'a>,
+        can_assign: bool,
+    ) -> Expr<'a> {
+        let index = Box::new(self.expression(scanner));
+        self.tokens
+            .consume(scanner, TokenKind::RightBracket, "Expect ']' after index");
+
+        let start = target.span;
+        let target = Box::new(target);
+        if can_assign && self.tokens.match_token(scanner, TokenKind::Equal) {
+            let value = Box::new(self.expression(scanner));
+            self.node(
+                start,
+                ExprKind::IndexSet {
+                    target,
+                    index,
+                    value,
+                },
+            )
+        } else {
+            self.node(start, ExprKind::Index { target, index })
+        }
+    }
+
+    fn literal(&mut self, _scanner: &mut Scanner<'a>, _can_assign: bool) -> Expr<'a> {
+        let literal = match self.tokens.prev.kind {
+            TokenKind::False => Literal::Bool(false),
+            TokenKind::Nil => Literal::Nil,
+            TokenKind::True => Literal::Bool(true),
+            _ => unreachable!(),
+        };
+        self.node(self.prev_span(), ExprKind::Literal(literal))
+    }
+
+    fn grouping(&mut self, scanner: &mut Scanner<'a>, _can_assign: bool) -> Expr<'a> {
+        let start = self.prev_span();
+        let inner = self.expression(scanner);
+        self.tokens.consume(
+            scanner,
+            TokenKind::RightParen,
+            "Expect ')' after expression",
+        );
+        self.node(start, ExprKind::Grouping(Box::new(inner)))
+    }
+
+    fn number(&mut self, _scanner: &mut Scanner<'a>, _can_assign: bool) -> Expr<'a> {
+        let literal = if self.tokens.prev.lexeme.contains('.') {
+            Literal::Number(self.tokens.prev.lexeme.parse().unwrap())
+        } else {
+            match self.tokens.prev.lexeme.parse() {
+                Ok(n) => Literal::Int(n),
+                Err(_) => {
+                    self.tokens.error("Integer literal is too large");
+                    return self.node(self.prev_span(), ExprKind::Error);
+                }
+            }
+        };
+        self.node(self.prev_span(), ExprKind::Literal(literal))
+    }
+
+    fn string(&mut self, _scanner: &mut Scanner<'a>, _can_assign: bool) -> Expr<'a> {
+        let contents = &self.tokens.prev.lexeme[1..self.tokens.prev.lexeme.len() - 1];
+        self.node(
+            self.prev_span(),
+            ExprKind::Literal(Literal::String(contents)),
+        )
+    }
+
+    fn unary(&mut self, scanner: &mut Scanner<'a>, _can_assign: bool) -> Expr<'a> {
+        let op = self.tokens.prev.kind;
+        let start = self.prev_span();
+
+        let operand = self.parse_precedence(scanner, Precedence::Unary);
+        self.node(
+            start,
+            ExprKind::Unary {
+                op,
+                operand: Box::new(operand),
+            },
+        )
+    }
+
+    fn parse_precedence(&mut self, scanner: &mut Scanner<'a>, precedence: Precedence) -> Expr<'a> {
+        self.tokens.advance(scanner);
+        let can_assign = precedence <= Precedence::Assignment;
+        let mut expr = match self.rules[&self.tokens.prev.kind].prefix {
+            None => {
+                self.tokens.error("Expect expression");
+                return self.node(self.prev_span(), ExprKind::Error);
+            }
+            Some(f) => f(self, scanner, can_assign),
+        };
+
+        while precedence <= self.rules[&self.tokens.curr.kind].precedence {
+            self.tokens.advance(scanner);
+            expr =
+                self.rules[&self.tokens.prev.kind].infix.unwrap()(self, scanner, expr, can_assign);
+        }
+
+        if can_assign && self.tokens.match_token(scanner, TokenKind::Equal) {
+            self.tokens.error("Invalid assignment target");
+        }
+        expr
+    }
+
+    fn expression(&mut self, scanner: &mut Scanner<'a>) -> Expr<'a> {
+        self.parse_precedence(scanner, Precedence::Assignment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_cover_whole_nodes() {
+        let script = parse("-(1 +\n 2)[0]").unwrap();
+        let ExprKind::Unary { operand, .. } = &script.body.kind else {
+            panic!("expected a unary node");
+        };
+        let ExprKind::Index { target, .. } = &operand.kind else {
+            panic!("expected an index node");
+        };
+
+        assert_eq!(
+            script.body.span,
+            Span {
+                start: 0,
+                end: 12,
+                line: 1,
+                end_line: 2
+            }
+        );
+        assert_eq!((target.span.start, target.span.end), (1, 9));
+        assert_eq!(
+            script.body.to_string(),
+            "Unary - [line 1, 0..12]\n  \
+               Index [line 1, 1..12]\n    \
+                 Grouping [line 1, 1..9]\n      \
+                   Binary + [line 1, 2..8]\n        \
+                     Literal 1 [line 1, 2..3]\n        \
+                     Literal 2 [line 2, 7..8]\n    \
+                 Literal 0 [line 2, 10..11]\n"
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use crate::chunk::{Chunk, OpCode};
+
+/// Fuses common instruction pairs into superinstructions:
+///
+///   Equal, Not        ->  NotEqual
+///   Less, Not         ->  GreaterEqual
+///   Greater, Not      ->  LessEqual
+///   Constant n, Add   ->  AddConstant n
+///
+/// A fused instruction takes the line of the half that can fail at runtime,
+/// so errors are reported where they were before. Nothing stores code
+/// offsets yet; once jumps exist, their targets will need remapping here.
+pub fn optimize(chunk: &mut Chunk) {
+    let instructions = decode(chunk);
+    let code = std::mem::take(&mut chunk.code);
+    chunk.lines.clear();
+
+    let mut i = 0;
+    while i < instructions.len() {
+        let (offset, op, line) = instructions[i];
+        let next = instructions.get(i + 1).map(|&(_, op, line)| (op, line));
+
+        // Operands always come from the first instruction of a pair
+        let operands = &code[offset + 1..offset + 1 + op.operand_bytes()];
+        let (op, line, len) = match (op, next) {
+            (OpCode::Equal, Some((OpCode::Not, _))) => (OpCode::NotEqual, line, 2),
+            (OpCode::Less, Some((OpCode::Not, _))) => (OpCode::GreaterEqual, line, 2),
+            (OpCode::Greater, Some((OpCode::Not, _))) => (OpCode::LessEqual, line, 2),
+            (OpCode::Constant, Some((OpCode::Add, add_line))) => (OpCode::AddConstant, add_line, 2),
+            _ => (op, line, 1),
+        };
+
+        chunk.write(op as u8, line);
+        for &byte in operands {
+            chunk.write(byte, line);
+        }
+        i += len;
+    }
+}
+
+/// Offset, opcode and line of every instruction in `chunk`.
+fn decode(chunk: &Chunk) -> Vec<(usize, OpCode, usize)> {
+    chunk
+        .instructions()
+        .map(|i| {
+            let op = i.opcode.expect("compiler emitted a valid opcode");
+            (i.offset, op, i.line)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::rvm::RVM;
+
+    #[test]
+    fn fuses_pairs_and_keeps_failing_lines() {
+        let mut vm = RVM::new();
+        let mut chunk = Chunk::new();
+        assert!(compile(
+            &mut vm,
+            "([1][0] != 2) == ([3][0] >=\n4) == ([5][0] <= 6) == ([7][0] + (\"s\"\n))",
+            &mut chunk
+        ));
+        optimize(&mut chunk);
+
+        let ops = decode(&chunk);
+        let has = |want: OpCode, want_line: usize| {
+            ops.iter()
+                .any(|&(_, op, line)| op as u8 == want as u8 && line == want_line)
+        };
+        assert!(ops
+            .iter()
+            .all(|(_, op, _)| !matches!(op, OpCode::Not | OpCode::Add)));
+        assert!(has(OpCode::NotEqual, 1));
+        assert!(has(OpCode::GreaterEqual, 2));
+        assert!(has(OpCode::LessEqual, 2));
+        assert!(has(OpCode::AddConstant, 3));
+    }
+}
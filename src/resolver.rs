@@ -0,0 +1,58 @@
+use crate::ast::{Expr, ExprKind, Script};
+
+/// Walks the tree between parsing and code generation. Nothing in the
+/// language declares a name yet, so there is nothing to bind and nothing to
+/// report. Scopes, and the analyses that need to see a whole function before
+/// any code is emitted for it, go here.
+///
+/// Once locals, functions and `return` exist, this is where unused locals and
+/// parameters, code after `return`, assignments that are never read and
+/// shadowed names get flagged, with a leading `_` silencing a name. Those go
+/// out through `compiler::report_warning` and leave `had_error` alone.
+pub fn resolve(script: &Script) -> bool {
+    let mut resolver = Resolver { had_error: false };
+    resolver.expr(&script.body);
+    !resolver.had_error
+}
+
+struct Resolver {
+    had_error: bool,
+}
+
+impl Resolver {
+    fn expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Literal(_) | ExprKind::Error => {}
+            ExprKind::Grouping(inner) => self.expr(inner),
+            ExprKind::Unary { operand, .. } => self.expr(operand),
+            ExprKind::Binary { lhs, rhs, .. } => {
+                self.expr(lhs);
+                self.expr(rhs);
+            }
+            ExprKind::List(items) => items.iter().for_each(|item| self.expr(item)),
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expr(key);
+                    self.expr(value);
+                }
+            }
+            ExprKind::Index { target, index } => {
+                self.expr(target);
+                self.expr(index);
+            }
+            ExprKind::IndexSet {
+                target,
+                index,
+                value,
+            } => {
+                self.expr(target);
+                self.expr(index);
+                self.expr(value);
+            }
+            ExprKind::Invoke { receiver, args, .. } => {
+                self.expr(receiver);
+                args.iter().for_each(|arg| self.expr(arg));
+            }
+        }
+    }
+}
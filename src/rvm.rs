@@ -1,63 +1,293 @@
+use crate::bytecode::{read_chunk, write_chunk};
 use crate::chunk::{Chunk, OpCode};
+#[cfg(feature = "ast-frontend")]
+use crate::codegen::compile;
+#[cfg(not(feature = "ast-frontend"))]
 use crate::compiler::compile;
-use crate::object::{Obj, ObjKind, ObjString};
+use crate::object::{Obj, ObjKind, ObjList, ObjMap, ObjRange, ObjString};
+use crate::parser::parse;
 use crate::value::Value;
-use num_traits::FromPrimitive;
-use std::ptr;
+use crate::verifier::{verify, StackUsage};
+use std::io::{self, Write};
+use std::mem::take;
+use std::{ptr, slice};
 
-const STACK_MAX: usize = 256;
+/// Slots allocated up front; the stack grows past this on demand.
+pub const STACK_INITIAL: usize = 256;
+/// Default limit on stack slots before reporting a stack overflow.
+pub const DEFAULT_STACK_MAX: usize = 1 << 16;
+/// Name of the top-level code in runtime errors and trace filters
+pub const SCRIPT_NAME: &str = "script";
 
 pub enum InterpretErr {
     Compile,
+    Load,
     Runtime,
 }
 
-pub fn interpret(source: &str) -> Result<(), InterpretErr> {
+pub fn interpret(source: &str, debug: DebugOptions) -> Result<(), InterpretErr> {
+    let mut vm = RVM::new();
+    vm.set_debug_options(debug);
+
+    let value = vm.interpret_source(source)?;
+    println!("{}", value);
+    Ok(())
+}
+
+pub fn interpret_bytecode(bytes: &[u8], debug: DebugOptions) -> Result<(), InterpretErr> {
+    let mut vm = RVM::new();
+    vm.set_debug_options(debug);
+
+    let chunk = read_chunk(&mut vm, bytes).map_err(|message| {
+        eprintln!("Invalid bytecode: {}", message);
+        InterpretErr::Load
+    })?;
+
+    let value = unsafe { vm.interpret(&chunk)? };
+    println!("{}", value);
+    Ok(())
+}
+
+pub fn compile_to_bytecode(source: &str) -> Result<Vec<u8>, InterpretErr> {
     let mut vm = RVM::new();
-    vm.reset_stack();
 
     let mut chunk = Chunk::new();
     if !compile(&mut vm, source, &mut chunk) {
-        Err(InterpretErr::Compile)
-    } else {
-        unsafe { vm.interpret(&chunk) }
+        return Err(InterpretErr::Compile);
     }
+
+    write_chunk(&chunk).map_err(|message| {
+        eprintln!("{}", message);
+        InterpretErr::Compile
+    })
+}
+
+/// Prints the syntax tree of `source` without compiling it.
+pub fn dump_ast(source: &str) -> Result<(), InterpretErr> {
+    let script = parse(source).ok_or(InterpretErr::Compile)?;
+    print!("{}", script.body);
+    Ok(())
+}
+
+/// Debug output that's switched on at runtime. `run` is compiled once with
+/// tracing and once without, so leaving it off costs nothing.
+pub struct DebugOptions {
+    /// Disassemble each chunk before running it
+    pub print_code: bool,
+    /// Show the stack and each instruction as it runs
+    pub trace: bool,
+    /// Only trace the function with this name
+    pub trace_filter: Option<String>,
+    /// Compile operators on constant operands as written instead of folding
+    /// them, so listings and benchmarks see every instruction
+    pub no_fold: bool,
+    /// Where code listings and traces are written, so they stay apart from
+    /// the program's own output
+    pub out: Box<dyn Write>,
 }
 
+impl Default for DebugOptions {
+    fn default() -> Self {
+        Self {
+            print_code: false,
+            trace: false,
+            trace_filter: None,
+            no_fold: false,
+            out: Box::new(io::stderr()),
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct RVM {
     ip: *const u8,
-    stack: [Value; STACK_MAX],
+    stack: Vec<Value>,
     stack_top: *mut Value,
+    max_stack: usize,
     objects: *mut Obj,
+    debug: DebugOptions,
+}
+
+impl Default for RVM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RVM {
     pub fn new() -> Self {
         Self {
             ip: ptr::null(),
-            stack: core::array::from_fn(|_| Value::default()),
+            stack: vec![Value::default(); STACK_INITIAL],
             stack_top: ptr::null_mut(),
+            max_stack: DEFAULT_STACK_MAX,
             objects: ptr::null_mut(),
+            debug: DebugOptions::default(),
+        }
+    }
+
+    pub fn set_debug_options(&mut self, debug: DebugOptions) {
+        self.debug = debug;
+    }
+
+    /// Takes the debug options back out, leaving the defaults in their place.
+    pub fn take_debug_options(&mut self) -> DebugOptions {
+        take(&mut self.debug)
+    }
+
+    /// Compiles and runs `source` on this VM. Everything it allocates stays on
+    /// this VM's heap, so values from one call are still valid in the next.
+    pub fn interpret_source(&mut self, source: &str) -> Result<Value, InterpretErr> {
+        let mut chunk = Chunk::new();
+        if !compile(self, source, &mut chunk) {
+            return Err(InterpretErr::Compile);
+        }
+
+        unsafe { self.interpret(&chunk) }
+    }
+
+    /// Runs `chunk` and returns the value of its expression.
+    ///
+    /// # Safety
+    ///
+    /// Object constants in `chunk` must have been allocated by this VM.
+    pub unsafe fn interpret(&mut self, chunk: &Chunk) -> Result<Value, InterpretErr> {
+        if self.debug.print_code {
+            let _ = chunk.disassemble(&mut *self.debug.out, SCRIPT_NAME);
+        }
+
+        match verify(chunk) {
+            Ok(usage) => self.run_verified(chunk, &usage),
+            Err(message) => {
+                eprintln!("Invalid bytecode: {}", message);
+                Err(InterpretErr::Load)
+            }
         }
     }
 
-    pub unsafe fn interpret(&mut self, chunk: &Chunk) -> Result<(), InterpretErr> {
+    /// Runs a chunk that has already been through `verify`, so callers that
+    /// run the same chunk repeatedly only pay for verification once.
+    ///
+    /// # Safety
+    ///
+    /// `usage` must be what `verify` returned for `chunk`, and object
+    /// constants in `chunk` must have been allocated by this VM.
+    pub unsafe fn run_verified(
+        &mut self,
+        chunk: &Chunk,
+        usage: &StackUsage,
+    ) -> Result<Value, InterpretErr> {
+        self.reset_stack();
+
+        if !self.reserve_stack(usage.depth) {
+            self.ip = chunk.code.as_ptr().add(usage.offset + 1);
+            self.runtime_error(chunk, "Stack overflow");
+            return Err(InterpretErr::Runtime);
+        }
+
         self.ip = chunk.code.as_ptr();
-        self.run(chunk)
+        if self.tracing(SCRIPT_NAME) {
+            self.run::<true>(chunk)
+        } else {
+            self.run::<false>(chunk)
+        }
+    }
+
+    /// Whether the compiler should fold operators on constant operands.
+    pub(crate) fn folds_constants(&self) -> bool {
+        !self.debug.no_fold
+    }
+
+    fn tracing(&self, name: &str) -> bool {
+        self.debug.trace && self.debug.trace_filter.as_ref().is_none_or(|f| f == name)
     }
 
     fn reset_stack(&mut self) {
         self.stack_top = self.stack.as_mut_ptr();
     }
 
+    /// Number of objects of each kind on the heap, in `ObjKind` order.
+    pub fn heap_stats(&self) -> Vec<(&'static str, usize)> {
+        let mut counts = [
+            ObjKind::String,
+            ObjKind::List,
+            ObjKind::Map,
+            ObjKind::Range,
+            ObjKind::Int,
+        ]
+        .map(|kind| (kind, 0));
+
+        let mut obj = self.objects;
+        while !obj.is_null() {
+            unsafe {
+                counts
+                    .iter_mut()
+                    .find(|(kind, _)| *kind == (*obj).kind)
+                    .unwrap()
+                    .1 += 1;
+                obj = (*obj).next;
+            }
+        }
+        counts.map(|(kind, n)| (kind.name(), n)).to_vec()
+    }
+
+    /// Maximum number of value slots the stack may grow to.
+    pub fn set_max_stack(&mut self, slots: usize) {
+        self.max_stack = slots;
+    }
+
+    /// Grows the stack to hold at least `depth` slots, moving `stack_top` along
+    /// with the values. Fails if that would exceed the maximum stack size.
+    unsafe fn reserve_stack(&mut self, depth: usize) -> bool {
+        if depth > self.max_stack {
+            return false;
+        }
+
+        if depth > self.stack.len() {
+            let used = self.stack_top.offset_from(self.stack.as_ptr()) as usize;
+            let slots = depth.next_power_of_two().min(self.max_stack);
+            self.stack.resize(slots, Value::default());
+            self.stack_top = self.stack.as_mut_ptr().add(used);
+        }
+        true
+    }
+
+    #[cold]
     fn runtime_error(&mut self, chunk: &Chunk, message: &str) {
         eprintln!("{}", message);
+        eprintln!("[line {}] in {}", self.error_line(chunk), SCRIPT_NAME);
 
+        self.reset_stack();
+    }
+
+    /// Line of the instruction that raised the last runtime error.
+    fn error_line(&self, chunk: &Chunk) -> usize {
+        // ip has moved past the failing instruction, so look up its last byte
         let offset = (self.ip as isize - chunk.code.as_ptr() as isize) / size_of::<u8>() as isize;
-        let line = chunk.lines[offset as usize];
-        eprintln!("[line {}] in script", line);
+        chunk.get_line(offset as usize - 1)
+    }
 
-        self.reset_stack();
+    /// Writes the stack and the instruction at `ip` to the debug output.
+    unsafe fn trace(
+        &mut self,
+        chunk: &Chunk,
+        ip: *const u8,
+        stack_top: *const Value,
+    ) -> io::Result<()> {
+        let out = &mut *self.debug.out;
+        write!(out, "          ")?;
+        let mut slot = self.stack.as_ptr();
+        while slot < stack_top {
+            write!(out, "[ {} ]", *slot)?;
+            slot = slot.add(1);
+        }
+        writeln!(out)?;
+
+        let offset = ip.offset_from(chunk.code.as_ptr()) as usize;
+        match chunk.instruction_at(offset) {
+            Some(instruction) => chunk.write_instruction(out, &instruction),
+            None => Ok(()),
+        }
     }
 
     pub(crate) unsafe fn allocate_obj<T>(&mut self, obj: Box<T>) -> *mut Obj {
@@ -67,144 +297,464 @@ impl RVM {
         obj
     }
 
-    unsafe fn read_byte(&mut self) -> u8 {
-        let byte = *self.ip;
-        self.ip = self.ip.add(1);
-        byte
+    /// An int value. With `nan-boxing`, ints too wide for the NaN payload are
+    /// allocated on the heap like any other object.
+    #[inline]
+    pub fn int(&mut self, n: i64) -> Value {
+        #[cfg(feature = "nan-boxing")]
+        if Value::inline_int(n).is_none() {
+            let obj = unsafe { self.allocate_obj(crate::object::ObjInt::new(n)) };
+            return Value::boxed_int(obj);
+        }
+        Value::int(n)
     }
 
-    unsafe fn read_constant<'a>(&mut self, chunk: &'a Chunk) -> &'a Value {
-        &chunk.constants.values[self.read_byte() as usize]
-    }
+    /// Runs verified bytecode. Opcodes are decoded without a validity check
+    /// and dispatched through a dense `match`, which compiles to a jump table.
+    /// `ip` lives in a local for the whole loop and is only written back to
+    /// `self.ip` when reporting a runtime error. `stack_top` is cached the
+    /// same way and written back when the chunk returns.
+    unsafe fn run<const TRACE: bool>(&mut self, chunk: &Chunk) -> Result<Value, InterpretErr> {
+        let mut ip = self.ip;
+        let mut stack_top = self.stack_top;
 
-    unsafe fn read_constant_long<'a>(&mut self, chunk: &'a Chunk) -> &'a Value {
-        let v1 = (self.read_byte() as usize) << 16;
-        let v2 = (self.read_byte() as usize) << 8;
-        let v3 = self.read_byte() as usize;
-        &chunk.constants.values[v1 | v2 | v3]
-    }
+        macro_rules! push {
+            ($value:expr) => {{
+                *stack_top = $value;
+                stack_top = stack_top.add(1);
+            }};
+        }
 
-    unsafe fn push(&mut self, value: Value) {
-        *self.stack_top = value;
-        self.stack_top = self.stack_top.add(1);
-    }
+        macro_rules! pop {
+            () => {{
+                stack_top = stack_top.sub(1);
+                &*stack_top
+            }};
+        }
 
-    unsafe fn pop<'a>(&mut self) -> &'a Value {
-        self.stack_top = self.stack_top.sub(1);
-        &*self.stack_top
-    }
+        macro_rules! read_byte {
+            () => {{
+                let byte = *ip;
+                ip = ip.add(1);
+                byte
+            }};
+        }
+
+        macro_rules! read_constant {
+            () => {
+                &chunk.constants.values[read_byte!() as usize]
+            };
+        }
+
+        macro_rules! read_constant_long {
+            () => {{
+                let v1 = (read_byte!() as usize) << 16;
+                let v2 = (read_byte!() as usize) << 8;
+                let v3 = read_byte!() as usize;
+                &chunk.constants.values[v1 | v2 | v3]
+            }};
+        }
+
+        macro_rules! runtime_error {
+            ($message:expr) => {{
+                self.ip = ip;
+                self.runtime_error(chunk, $message);
+                return Err(InterpretErr::Runtime);
+            }};
+        }
+
+        macro_rules! unary_op {
+            ($op_fn:ident) => {
+                let v = pop!();
+                match self.$op_fn(v) {
+                    Ok(v) => push!(v),
+                    Err(err) => runtime_error!(err.message()),
+                }
+            };
+        }
 
-    unsafe fn run(&mut self, chunk: &Chunk) -> Result<(), InterpretErr> {
         macro_rules! binary_op {
-            ($value_enum:expr, $op:tt) => {
-                let b = self.pop();
-                let a = self.pop();
-                if let Value::Number(b) = b
-                    && let Value::Number(a) = a
-                {
-                    self.push($value_enum(a $op b))
-                } else {
-                    self.runtime_error(chunk, "Operands must be numbers");
-                    return Err(InterpretErr::Runtime);
+            ($op_fn:ident) => {
+                binary_op!($op_fn, pop!())
+            };
+            ($op_fn:ident, $b:expr) => {
+                let b = $b;
+                let a = pop!();
+                match self.$op_fn(a, b) {
+                    Ok(c) => push!(c),
+                    Err(err) => runtime_error!(err.message()),
                 }
             };
         }
 
         loop {
-            #[cfg(feature = "debug-trace-execution")]
-            {
-                print!("          ");
-                let mut slot = self.stack.as_ptr();
-                while slot < self.stack_top {
-                    print!("[ ");
-                    print!("{}", *slot);
-                    print!(" ]");
-                    slot = slot.add(1);
-                }
-                println!();
-                let offset =
-                    (self.ip as isize - chunk.code.as_ptr() as isize) / size_of::<u8>() as isize;
-                chunk.disassemble_instruction(offset as usize);
+            if TRACE {
+                // A failed write shouldn't stop the program being traced
+                let _ = self.trace(chunk, ip, stack_top);
             }
 
-            match OpCode::from_u8(self.read_byte()) {
-                Some(OpCode::Constant) => {
-                    let constant = self.read_constant(chunk);
-                    self.push(constant.clone());
+            match OpCode::from_u8_unchecked(read_byte!()) {
+                OpCode::Constant => {
+                    let constant = read_constant!();
+                    push!(constant.clone());
                 }
-                Some(OpCode::ConstantLong) => {
-                    let constant = self.read_constant_long(chunk);
-                    self.push(constant.clone());
+                OpCode::ConstantLong => {
+                    let constant = read_constant_long!();
+                    push!(constant.clone());
                 }
-                Some(OpCode::Nil) => {
-                    self.push(Value::Nil);
+                OpCode::Nil => {
+                    push!(Value::nil());
                 }
-                Some(OpCode::True) => {
-                    self.push(Value::Boolean(true));
+                OpCode::True => {
+                    push!(Value::boolean(true));
                 }
-                Some(OpCode::False) => {
-                    self.push(Value::Boolean(false));
+                OpCode::False => {
+                    push!(Value::boolean(false));
                 }
-                Some(OpCode::Equal) => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(Value::Boolean(a == b))
+                OpCode::Equal => {
+                    let b = pop!();
+                    let a = pop!();
+                    push!(Value::boolean(a == b))
                 }
-                Some(OpCode::Greater) => {
-                    binary_op!(Value::Boolean, >);
+                OpCode::NotEqual => {
+                    let b = pop!();
+                    let a = pop!();
+                    push!(Value::boolean(a != b))
                 }
-                Some(OpCode::Less) => {
-                    binary_op!(Value::Boolean, <);
+                OpCode::Greater => {
+                    binary_op!(greater);
                 }
-                Some(OpCode::Add) => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    if let Value::Number(b) = b
-                        && let Value::Number(a) = a
-                    {
-                        self.push(Value::Number(a + b))
-                    } else if let Value::Obj(b) = b
-                        && (**b).kind == ObjKind::String
-                        && let Value::Obj(a) = a
-                        && (**a).kind == ObjKind::String
+                OpCode::GreaterEqual => {
+                    binary_op!(greater_equal);
+                }
+                OpCode::Less => {
+                    binary_op!(less);
+                }
+                OpCode::LessEqual => {
+                    binary_op!(less_equal);
+                }
+                OpCode::Add => {
+                    binary_op!(add);
+                }
+                OpCode::AddConstant => {
+                    binary_op!(add, read_constant!());
+                }
+                OpCode::Subtract => {
+                    binary_op!(subtract);
+                }
+                OpCode::Multiply => {
+                    binary_op!(multiply);
+                }
+                OpCode::Divide => {
+                    binary_op!(divide);
+                }
+                OpCode::IntDivide => {
+                    binary_op!(floor_divide);
+                }
+                OpCode::Modulo => {
+                    binary_op!(modulo);
+                }
+                OpCode::Power => {
+                    binary_op!(power);
+                }
+                OpCode::BitAnd => {
+                    binary_op!(bit_and);
+                }
+                OpCode::BitOr => {
+                    binary_op!(bit_or);
+                }
+                OpCode::BitXor => {
+                    binary_op!(bit_xor);
+                }
+                OpCode::ShiftLeft => {
+                    binary_op!(shift_left);
+                }
+                OpCode::ShiftRight => {
+                    binary_op!(shift_right);
+                }
+                OpCode::Not => {
+                    let v = pop!();
+                    push!(Value::boolean(!v.is_falsey()));
+                }
+                OpCode::Negate => {
+                    unary_op!(negate);
+                }
+                OpCode::BitNot => {
+                    unary_op!(bit_not);
+                }
+                op @ (OpCode::Range | OpCode::RangeInclusive) => {
+                    let end = pop!();
+                    let start = pop!();
+                    if let Some(end) = end.as_int()
+                        && let Some(start) = start.as_int()
                     {
-                        let b = &(*(*b as *mut ObjString)).value;
-                        let a = &(*(*a as *mut ObjString)).value;
-                        let c = Value::Obj(self.allocate_obj(ObjString::new(a.to_string() + b)));
-                        self.push(c);
+                        let inclusive = matches!(op, OpCode::RangeInclusive);
+                        let range = ObjRange::new(start, end, inclusive, 1);
+                        let range = Value::obj(self.allocate_obj(range));
+                        push!(range);
                     } else {
-                        self.runtime_error(chunk, "Operands must be numbers or strings");
-                        return Err(InterpretErr::Runtime);
+                        runtime_error!("Range bounds must be integers");
                     }
                 }
-                Some(OpCode::Subtract) => {
-                    binary_op!(Value::Number, -);
+                OpCode::BuildList => {
+                    let count = read_byte!() as usize;
+                    let items = slice::from_raw_parts(stack_top.sub(count), count).to_vec();
+                    stack_top = stack_top.sub(count);
+                    let list = Value::obj(self.allocate_obj(ObjList::new(items)));
+                    push!(list);
                 }
-                Some(OpCode::Multiply) => {
-                    binary_op!(Value::Number, *);
+                OpCode::BuildMap => {
+                    let count = read_byte!() as usize;
+                    let entries = slice::from_raw_parts(stack_top.sub(count * 2), count * 2);
+                    let mut map = ObjMap::new();
+                    for pair in entries.chunks(2) {
+                        if let Err(message) = map.insert(pair[0].clone(), pair[1].clone()) {
+                            runtime_error!(&message);
+                        }
+                    }
+                    stack_top = stack_top.sub(count * 2);
+                    let map = Value::obj(self.allocate_obj(map));
+                    push!(map);
                 }
-                Some(OpCode::Divide) => {
-                    binary_op!(Value::Number, /);
+                OpCode::IndexGet => {
+                    let index = pop!();
+                    let target = pop!();
+                    match self.index_get(target, index) {
+                        Ok(v) => push!(v),
+                        Err(message) => {
+                            runtime_error!(&message);
+                        }
+                    }
                 }
-                Some(OpCode::Not) => {
-                    let v = self.pop();
-                    self.push(Value::Boolean(!v.is_falsey()));
+                OpCode::IndexSet => {
+                    let value = pop!().clone();
+                    let index = pop!();
+                    let target = pop!();
+                    match self.index_set(target, index, value.clone()) {
+                        Ok(()) => push!(value),
+                        Err(message) => {
+                            runtime_error!(&message);
+                        }
+                    }
                 }
-                Some(OpCode::Negate) => {
-                    let v = self.pop();
-                    if let Value::Number(v) = v {
-                        self.push(Value::Number(-v))
-                    } else {
-                        self.runtime_error(chunk, "Operand must be a number");
-                        return Err(InterpretErr::Runtime);
+                OpCode::Invoke => {
+                    let Some(name) = read_constant!().as_obj() else {
+                        unreachable!()
+                    };
+                    let name = &(*(name as *mut ObjString)).value;
+                    let arg_count = read_byte!() as usize;
+                    let args = slice::from_raw_parts(stack_top.sub(arg_count), arg_count);
+                    let receiver = &*stack_top.sub(arg_count + 1);
+                    match self.invoke(receiver, name, args) {
+                        Ok(v) => {
+                            stack_top = stack_top.sub(arg_count + 1);
+                            push!(v);
+                        }
+                        Err(message) => {
+                            runtime_error!(&message);
+                        }
                     }
                 }
-                Some(OpCode::Return) => {
-                    println!("{}", self.pop());
-                    return Ok(());
+                OpCode::Return => {
+                    let value = pop!().clone();
+                    self.stack_top = stack_top;
+                    return Ok(value);
                 }
-                _ => (),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `source` with folding off, so every operator runs on the VM.
+    /// Returns the result, or the line of the runtime error.
+    fn run(vm: &mut RVM, source: &str) -> Result<String, usize> {
+        vm.set_debug_options(DebugOptions {
+            no_fold: true,
+            ..DebugOptions::default()
+        });
+        let mut chunk = Chunk::new();
+        assert!(compile(vm, source, &mut chunk), "{source}");
+        match unsafe { vm.interpret(&chunk) } {
+            Ok(value) => Ok(value.to_string()),
+            Err(InterpretErr::Runtime) => Err(vm.error_line(&chunk)),
+            Err(_) => panic!("{source} did not load"),
+        }
+    }
+
+    /// `1 - (2 - (... - n))` with each number on its own line. The stack is
+    /// deepest, at `n` slots, when `n` is pushed on line `n`.
+    fn nested(n: usize) -> String {
+        let mut source = (1..n).map(|i| format!("{i} -\n(")).collect::<String>();
+        source += &n.to_string();
+        source + &")".repeat(n - 1)
+    }
+
+    #[test]
+    fn stack_grows_past_its_initial_size() {
+        let mut vm = RVM::new();
+        let depth = STACK_INITIAL + 44;
+        assert_eq!(run(&mut vm, &nested(depth)).as_deref(), Ok("-150"));
+        assert_eq!(vm.stack.len(), 512);
+
+        vm.set_max_stack(depth);
+        assert_eq!(run(&mut vm, &nested(depth)).as_deref(), Ok("-150"));
+    }
+
+    #[test]
+    fn stack_overflow_reports_the_deepest_line() {
+        let mut vm = RVM::new();
+        vm.set_max_stack(8);
+        assert_eq!(run(&mut vm, &nested(8)).as_deref(), Ok("-4"));
+        assert_eq!(run(&mut vm, &nested(12)), Err(12));
+        assert_eq!(vm.stack.len(), STACK_INITIAL);
+    }
+
+    #[test]
+    fn int_overflow_is_a_runtime_error() {
+        let mut vm = RVM::new();
+        let min = "(-9223372036854775807 - 1)";
+        for source in [
+            "9223372036854775807 + 1".to_string(),
+            format!("{min} - 1"),
+            "4611686018427387904 * 2".to_string(),
+            format!("-{min}"),
+            format!("{min} // -1"),
+            "3 ** 40".to_string(),
+        ] {
+            assert_eq!(run(&mut vm, &source), Err(1), "{source}");
+        }
+        assert_eq!(run(&mut vm, "1 +\n9223372036854775806 +\n1"), Err(3));
+        assert_eq!(
+            run(&mut vm, "9223372036854775806 + 1"),
+            Ok("9223372036854775807".to_string())
+        );
+    }
+
+    #[test]
+    fn mixing_ints_and_floats_promotes() {
+        let mut vm = RVM::new();
+        for (source, expected) in [
+            ("1 + 2", "3"),
+            ("1 + 2.5", "3.5"),
+            ("2.5 * 2", "5"),
+            ("7 / 2", "3.5"),
+            ("6 / 3", "2"),
+            ("7 // 2", "3"),
+            ("-7 // 2", "-4"),
+            ("7.5 // 2", "3"),
+            ("-7 % 3", "2"),
+            ("7 % -3", "-2"),
+            ("7.5 % 2", "1.5"),
+            ("9223372036854775807 + 1.0", "9223372036854776000"),
+        ] {
+            assert_eq!(run(&mut vm, source).as_deref(), Ok(expected), "{source}");
+        }
+        assert_eq!(run(&mut vm, "1 // 0"), Err(1));
+        assert_eq!(run(&mut vm, "1 % 0"), Err(1));
+        assert_eq!(run(&mut vm, "1 / 0").as_deref(), Ok("inf"));
+    }
+
+    #[test]
+    fn power_is_right_associative_and_binds_tighter_than_minus() {
+        let mut vm = RVM::new();
+        for (source, expected) in [
+            ("2 ** 3 ** 2", "512"),
+            ("(2 ** 3) ** 2", "64"),
+            ("2 ** 3 ** 2 == 512", "true"),
+            ("-2 ** 2", "-4"),
+            ("(-2) ** 2", "4"),
+            ("2 ** -1", "0.5"),
+            ("2 ** -1 ** 2", "0.5"),
+            ("4 ** 0.5", "2"),
+            ("0 ** 0", "1"),
+            ("0 ** -1", "inf"),
+            ("(-1) ** 9223372036854775807", "-1"),
+        ] {
+            assert_eq!(run(&mut vm, source).as_deref(), Ok(expected), "{source}");
+        }
+        assert_eq!(run(&mut vm, "2 ** 63"), Err(1));
+    }
+
+    #[test]
+    fn bitwise_operators_take_ints() {
+        let mut vm = RVM::new();
+        for (source, expected) in [
+            ("12 & 10", "8"),
+            ("12 | 10", "14"),
+            ("12 ^ 10", "6"),
+            ("~5", "-6"),
+            ("~-1", "0"),
+            ("-~5", "6"),
+            ("~2 ** 2", "-5"),
+            ("1 << 4", "16"),
+            ("1 << 63", "-9223372036854775808"),
+            ("-8 >> 1", "-4"),
+            ("5 >> 63", "0"),
+        ] {
+            assert_eq!(run(&mut vm, source).as_deref(), Ok(expected), "{source}");
+        }
+
+        for source in [
+            "1.0 & 1",
+            "1 | true",
+            "\"a\" ^ 1",
+            "1 << 1.0",
+            "2.0 >> 1",
+            "~1.5",
+            "~nil",
+        ] {
+            assert_eq!(run(&mut vm, source), Err(1), "{source}");
+        }
+    }
+
+    #[test]
+    fn shift_amounts_must_be_in_range() {
+        let mut vm = RVM::new();
+        for source in [
+            "1 << 64",
+            "1 << -1",
+            "1 >> 64",
+            "1 >> -1",
+            "0 << 9223372036854775807",
+        ] {
+            assert_eq!(run(&mut vm, source), Err(1), "{source}");
+        }
+    }
+
+    #[test]
+    fn bitwise_operators_bind_tighter_than_comparisons() {
+        let mut vm = RVM::new();
+        for (source, expected) in [
+            // flags & MASK == MASK
+            ("6 & 4 == 4", "true"),
+            ("6 & 1 == 1", "false"),
+            ("13 & 4 != 0", "true"),
+            ("1 | 2 < 4", "true"),
+            ("1 | 2 ^ 3 & 4", "3"),
+            ("1 << 2 + 1", "8"),
+            ("2 * 3 & 5", "4"),
+        ] {
+            assert_eq!(run(&mut vm, source).as_deref(), Ok(expected), "{source}");
+        }
+    }
+
+    #[test]
+    fn ints_equal_floats_of_the_same_value() {
+        let mut vm = RVM::new();
+        for (source, expected) in [
+            ("1 == 1.0", "true"),
+            ("1.0 == 1", "true"),
+            ("1 != 1.0", "false"),
+            ("0 == -0.0", "true"),
+            ("1 == 1.5", "false"),
+            ("1 < 1.5", "true"),
+            ("2 >= 2.0", "true"),
+        ] {
+            assert_eq!(run(&mut vm, source).as_deref(), Ok(expected), "{source}");
+        }
+    }
+}
@@ -4,13 +4,25 @@ pub enum TokenKind {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
+    DotDot,
+    DotDotEqual,
     Minus,
     Plus,
     Semicolon,
     Slash,
+    SlashSlash,
     Star,
+    StarStar,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
 
     Bang,
     BangEqual,
@@ -18,8 +30,10 @@ pub enum TokenKind {
     EqualEqual,
     Greater,
     GreaterEqual,
+    GreaterGreater,
     Less,
     LessEqual,
+    LessLess,
 
     Identifier,
     String,
@@ -46,6 +60,8 @@ pub enum TokenKind {
     Eof,
 }
 
+const UNTERMINATED_STRING: &str = "Unterminated string";
+
 pub struct Token<'a> {
     pub kind: TokenKind,
     pub lexeme: &'a str,
@@ -103,13 +119,44 @@ impl<'a> Scanner<'a> {
             ')' => self.make_token(TokenKind::RightParen),
             '{' => self.make_token(TokenKind::LeftBrace),
             '}' => self.make_token(TokenKind::RightBrace),
+            '[' => self.make_token(TokenKind::LeftBracket),
+            ']' => self.make_token(TokenKind::RightBracket),
             ';' => self.make_token(TokenKind::Semicolon),
             ',' => self.make_token(TokenKind::Comma),
-            '.' => self.make_token(TokenKind::Dot),
+            ':' => self.make_token(TokenKind::Colon),
+            '.' => {
+                let kind = if !self.advance_match('.') {
+                    TokenKind::Dot
+                } else if self.advance_match('=') {
+                    TokenKind::DotDotEqual
+                } else {
+                    TokenKind::DotDot
+                };
+                self.make_token(kind)
+            }
             '-' => self.make_token(TokenKind::Minus),
             '+' => self.make_token(TokenKind::Plus),
-            '/' => self.make_token(TokenKind::Slash),
-            '*' => self.make_token(TokenKind::Star),
+            '/' => {
+                let kind = if self.advance_match('/') {
+                    TokenKind::SlashSlash
+                } else {
+                    TokenKind::Slash
+                };
+                self.make_token(kind)
+            }
+            '*' => {
+                let kind = if self.advance_match('*') {
+                    TokenKind::StarStar
+                } else {
+                    TokenKind::Star
+                };
+                self.make_token(kind)
+            }
+            '%' => self.make_token(TokenKind::Percent),
+            '&' => self.make_token(TokenKind::Ampersand),
+            '|' => self.make_token(TokenKind::Pipe),
+            '^' => self.make_token(TokenKind::Caret),
+            '~' => self.make_token(TokenKind::Tilde),
             '!' => {
                 let kind = if self.advance_match('=') {
                     TokenKind::BangEqual
@@ -129,6 +176,8 @@ impl<'a> Scanner<'a> {
             '<' => {
                 let kind = if self.advance_match('=') {
                     TokenKind::LessEqual
+                } else if self.advance_match('<') {
+                    TokenKind::LessLess
                 } else {
                     TokenKind::Less
                 };
@@ -137,6 +186,8 @@ impl<'a> Scanner<'a> {
             '>' => {
                 let kind = if self.advance_match('=') {
                     TokenKind::GreaterEqual
+                } else if self.advance_match('>') {
+                    TokenKind::GreaterGreater
                 } else {
                     TokenKind::Greater
                 };
@@ -190,13 +241,9 @@ impl<'a> Scanner<'a> {
             } else if c == '\n' {
                 self.line += 1;
                 self.advance();
-            } else if c == '/' {
-                if self.peek(1) == '/' {
-                    while !self.is_at_end() && self.peek(0) != '\n' {
-                        self.advance();
-                    }
-                } else {
-                    return;
+            } else if c == '#' {
+                while !self.is_at_end() && self.peek(0) != '\n' {
+                    self.advance();
                 }
             } else {
                 return;
@@ -290,7 +337,7 @@ impl<'a> Scanner<'a> {
             self.advance(); // closing quote
             self.make_token(TokenKind::String)
         } else {
-            self.error_token("Unterminated string")
+            self.error_token(UNTERMINATED_STRING)
         }
     }
 
@@ -310,3 +357,55 @@ impl<'a> Scanner<'a> {
         }
     }
 }
+
+/// Whether `source` stops partway through an expression, inside an open
+/// bracket or an unterminated string, so more input could still complete it.
+pub fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source);
+    let mut depth = 0i64;
+    loop {
+        let token = scanner.scan_token();
+        match token.kind {
+            TokenKind::LeftParen | TokenKind::LeftBracket | TokenKind::LeftBrace => depth += 1,
+            TokenKind::RightParen | TokenKind::RightBracket | TokenKind::RightBrace => depth -= 1,
+            TokenKind::Error if token.lexeme == UNTERMINATED_STRING => return true,
+            TokenKind::Eof => return depth > 0,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_brackets_and_strings_are_incomplete() {
+        assert!(is_incomplete("[1, (2"));
+        assert!(is_incomplete("{\"a\": [1]"));
+        assert!(is_incomplete("\"abc\n"));
+        assert!(!is_incomplete("[1, (2)]"));
+        assert!(!is_incomplete("1 +"));
+        assert!(!is_incomplete("(1))"));
+        assert!(!is_incomplete("\"(\""));
+    }
+
+    #[test]
+    fn hash_starts_a_comment_and_slash_slash_divides() {
+        let mut scanner = Scanner::new("7 // 2 # halves // not code\n# line\n3");
+        let tokens: Vec<_> = std::iter::from_fn(|| {
+            let token = scanner.scan_token();
+            (token.kind != TokenKind::Eof).then_some((token.kind, token.line))
+        })
+        .collect();
+        assert_eq!(
+            tokens,
+            [
+                (TokenKind::Number, 1),
+                (TokenKind::SlashSlash, 1),
+                (TokenKind::Number, 1),
+                (TokenKind::Number, 3),
+            ]
+        );
+    }
+}
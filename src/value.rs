@@ -1,87 +1,390 @@
-use crate::object::{Obj, ObjKind, ObjString};
-use std::fmt::{Display, Formatter};
-use std::mem::discriminant;
+use crate::object::{Obj, ObjInt, ObjKind, ObjList, ObjMap, ObjRange, ObjString};
+use std::cmp::Ordering;
+use std::fmt::{Debug, Display, Formatter};
+use std::ptr;
 
-#[derive(Clone, Debug)]
-pub enum Value {
+/// A decoded `Value`, for matching on. `Value` itself hides its layout so the
+/// `nan-boxing` feature can pack it into a single word.
+#[derive(Copy, Clone, Debug, Default)]
+pub enum Unpacked {
     Boolean(bool),
+    #[default]
     Nil,
+    Int(i64),
     Number(f64),
     Obj(*mut Obj),
 }
 
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Clone, Default)]
+pub struct Value(Unpacked);
+
+#[cfg(not(feature = "nan-boxing"))]
 impl Value {
-    pub fn is_falsey(&self) -> bool {
-        match self {
-            Value::Boolean(b) => *b,
-            Value::Nil => true,
-            Value::Number(n) => *n != 0.0,
-            Value::Obj(_) => false,
-        }
+    #[inline]
+    pub fn nil() -> Self {
+        Value(Unpacked::Nil)
+    }
+
+    #[inline]
+    pub fn boolean(b: bool) -> Self {
+        Value(Unpacked::Boolean(b))
+    }
+
+    /// Outside the crate, ints are made with `RVM::int`, which also handles
+    /// the wide ones `nan-boxing` keeps on the heap.
+    #[inline]
+    pub(crate) fn int(n: i64) -> Self {
+        Value(Unpacked::Int(n))
     }
+
+    #[inline]
+    pub fn number(n: f64) -> Self {
+        Value(Unpacked::Number(n))
+    }
+
+    #[inline]
+    pub fn obj(obj: *mut Obj) -> Self {
+        Value(Unpacked::Obj(obj))
+    }
+
+    #[inline]
+    pub fn unpack(&self) -> Unpacked {
+        self.0
+    }
+}
+
+// Every double that isn't a quiet NaN is stored as is. Everything else lives
+// in the payload of a quiet NaN:
+//
+//   nil, false, true   QNAN | 1, 2, 3
+//   small int          QNAN | INT_BIT | 49-bit two's complement
+//   object             SIGN_BIT | QNAN | pointer
+//   large int          SIGN_BIT | QNAN | INT_BIT | pointer to an `ObjInt`
+//
+// NaNs produced by arithmetic are canonicalized so they never look tagged.
+#[cfg(feature = "nan-boxing")]
+mod packing {
+    pub const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    pub const QNAN: u64 = 0x7ffc_0000_0000_0000;
+    pub const INT_BIT: u64 = 1 << 49;
+    pub const PTR_MASK: u64 = (1 << 48) - 1;
+    pub const INT_PAYLOAD_BITS: u32 = 49;
+
+    pub const TAG_NIL: u64 = 1;
+    pub const TAG_FALSE: u64 = 2;
+    pub const TAG_TRUE: u64 = 3;
 }
 
+#[cfg(feature = "nan-boxing")]
+use packing::*;
+
+#[cfg(feature = "nan-boxing")]
+#[derive(Clone)]
+pub struct Value(u64);
+
+#[cfg(feature = "nan-boxing")]
 impl Default for Value {
     fn default() -> Self {
-        Value::Nil
+        Value::nil()
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+impl Value {
+    #[inline]
+    pub fn nil() -> Self {
+        Value(QNAN | TAG_NIL)
+    }
+
+    #[inline]
+    pub fn boolean(b: bool) -> Self {
+        Value(QNAN | if b { TAG_TRUE } else { TAG_FALSE })
+    }
+
+    /// An int that fits in the NaN payload. Wider ints live on the VM heap,
+    /// so anything that may produce one goes through `RVM::int` instead.
+    ///
+    /// Panics if `n` needs more than 49 bits.
+    #[inline]
+    pub(crate) fn int(n: i64) -> Self {
+        Self::inline_int(n).expect("int does not fit in a NaN-boxed value")
+    }
+
+    #[inline]
+    pub(crate) fn inline_int(n: i64) -> Option<Self> {
+        let shift = 64 - INT_PAYLOAD_BITS;
+        if (n << shift) >> shift == n {
+            Some(Value(
+                QNAN | INT_BIT | (n as u64 & ((1 << INT_PAYLOAD_BITS) - 1)),
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// A wide int allocated by `RVM::int`.
+    #[inline]
+    pub(crate) fn boxed_int(obj: *mut Obj) -> Self {
+        debug_assert_eq!(obj as u64 & !PTR_MASK, 0);
+        Value(SIGN_BIT | QNAN | INT_BIT | obj as u64)
+    }
+
+    #[inline]
+    pub fn number(n: f64) -> Self {
+        if n.is_nan() {
+            Value(f64::NAN.to_bits())
+        } else {
+            Value(n.to_bits())
+        }
+    }
+
+    #[inline]
+    pub fn obj(obj: *mut Obj) -> Self {
+        debug_assert_eq!(obj as u64 & !PTR_MASK, 0);
+        Value(SIGN_BIT | QNAN | obj as u64)
+    }
+
+    #[inline]
+    pub fn unpack(&self) -> Unpacked {
+        let bits = self.0;
+        if bits & QNAN != QNAN {
+            Unpacked::Number(f64::from_bits(bits))
+        } else if bits & SIGN_BIT != 0 {
+            let ptr = bits & PTR_MASK;
+            if bits & INT_BIT != 0 {
+                Unpacked::Int(unsafe { (*(ptr as *const ObjInt)).value })
+            } else {
+                Unpacked::Obj(ptr as *mut Obj)
+            }
+        } else if bits & INT_BIT != 0 {
+            let shift = 64 - INT_PAYLOAD_BITS;
+            Unpacked::Int(((bits << shift) as i64) >> shift)
+        } else {
+            match bits & !QNAN {
+                TAG_NIL => Unpacked::Nil,
+                TAG_FALSE => Unpacked::Boolean(false),
+                _ => Unpacked::Boolean(true),
+            }
+        }
+    }
+}
+
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        match self.unpack() {
+            Unpacked::Boolean(b) => b,
+            Unpacked::Nil => true,
+            Unpacked::Int(n) => n != 0,
+            Unpacked::Number(n) => n != 0.0,
+            Unpacked::Obj(_) => false,
+        }
+    }
+
+    #[inline]
+    pub fn is_nil(&self) -> bool {
+        matches!(self.unpack(), Unpacked::Nil)
+    }
+
+    #[inline]
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.unpack() {
+            Unpacked::Boolean(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn as_int(&self) -> Option<i64> {
+        match self.unpack() {
+            Unpacked::Int(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn as_number(&self) -> Option<f64> {
+        match self.unpack() {
+            Unpacked::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn as_obj(&self) -> Option<*mut Obj> {
+        match self.unpack() {
+            Unpacked::Obj(obj) => Some(obj),
+            _ => None,
+        }
+    }
+
+    /// Numeric value widened to a float, used when an int meets a float.
+    #[inline]
+    pub fn as_f64(&self) -> Option<f64> {
+        match self.unpack() {
+            Unpacked::Int(n) => Some(n as f64),
+            Unpacked::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self.unpack() {
+            Unpacked::Boolean(_) => "bool",
+            Unpacked::Nil => "nil",
+            Unpacked::Int(_) => "int",
+            Unpacked::Number(_) => "float",
+            Unpacked::Obj(obj) => unsafe { (*obj).kind.name() },
+        }
+    }
+
+    /// The object if this is one of the given kind.
+    pub(crate) unsafe fn as_obj_of(&self, kind: ObjKind) -> Option<*mut Obj> {
+        self.as_obj().filter(|&obj| (*obj).kind == kind)
+    }
+}
+
+impl Debug for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Value").field(&self.unpack()).finish()
+    }
+}
+
+/// Exact comparison, so ints beyond 2^53 don't compare equal to a nearby float.
+fn int_eq_float(i: i64, f: f64) -> bool {
+    i as f64 == f && f < i64::MAX as f64 && f as i64 == i
+}
+
+/// Exact ordering of an int against a float, without rounding the int.
+fn int_cmp_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if f >= i64::MAX as f64 {
+        Some(Ordering::Less)
+    } else if f < i64::MIN as f64 {
+        Some(Ordering::Greater)
+    } else {
+        let whole = f.trunc();
+        Some(
+            i.cmp(&(whole as i64))
+                .then_with(|| 0.0.partial_cmp(&(f - whole)).unwrap()),
+        )
     }
 }
 
 impl Display for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::Boolean(b) => write!(f, "{}", b),
-            Value::Nil => write!(f, "nil"),
-            Value::Number(n) => write!(f, "{}", n),
-            Value::Obj(obj) => unsafe {
-                match (**obj).kind {
+        match self.unpack() {
+            Unpacked::Boolean(b) => write!(f, "{}", b),
+            Unpacked::Nil => write!(f, "nil"),
+            Unpacked::Int(n) => write!(f, "{}", n),
+            Unpacked::Number(n) => write!(f, "{}", n),
+            Unpacked::Obj(obj) => unsafe {
+                match (*obj).kind {
                     ObjKind::String => {
-                        let obj_s = *obj as *mut ObjString;
+                        let obj_s = obj as *mut ObjString;
                         write!(f, "{}", (*obj_s).value)
                     }
+                    ObjKind::List => {
+                        let obj_l = obj as *mut ObjList;
+                        write!(f, "[")?;
+                        for (i, item) in (*obj_l).items.iter().enumerate() {
+                            if i > 0 {
+                                write!(f, ", ")?;
+                            }
+                            item.fmt_nested(f)?;
+                        }
+                        write!(f, "]")
+                    }
+                    ObjKind::Map => {
+                        let obj_m = obj as *mut ObjMap;
+                        write!(f, "{{")?;
+                        for (i, (key, value)) in (*obj_m).entries.iter().enumerate() {
+                            if i > 0 {
+                                write!(f, ", ")?;
+                            }
+                            key.fmt_nested(f)?;
+                            write!(f, ": ")?;
+                            value.fmt_nested(f)?;
+                        }
+                        write!(f, "}}")
+                    }
+                    ObjKind::Range => {
+                        let r = &*(obj as *mut ObjRange);
+                        let op = if r.inclusive { "..=" } else { ".." };
+                        if r.step == 1 {
+                            write!(f, "{}{}{}", r.start, op, r.end)
+                        } else {
+                            write!(f, "({}{}{}).step({})", r.start, op, r.end, r.step)
+                        }
+                    }
+                    ObjKind::Int => write!(f, "{}", (*(obj as *mut ObjInt)).value),
                 }
             },
         }
     }
 }
 
+impl Value {
+    /// Writes the value as an item of a collection, with strings quoted so
+    /// `["1", 1]` doesn't print as `[1, 1]`.
+    fn fmt_nested(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match unsafe { self.as_obj_of(ObjKind::String) } {
+            Some(obj) => write!(f, "\"{}\"", unsafe { &(*(obj as *mut ObjString)).value }),
+            None => write!(f, "{}", self),
+        }
+    }
+}
+
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
-        match self {
-            Value::Boolean(a) => match other {
-                Value::Boolean(b) => a == b,
-                _ => false,
-            },
-            Value::Nil => match other {
-                Value::Nil => true,
-                _ => false,
-            },
-            Value::Number(a) => match other {
-                Value::Number(b) => a == b,
-                _ => false,
+        match (self.unpack(), other.unpack()) {
+            (Unpacked::Boolean(a), Unpacked::Boolean(b)) => a == b,
+            (Unpacked::Nil, Unpacked::Nil) => true,
+            (Unpacked::Int(a), Unpacked::Int(b)) => a == b,
+            (Unpacked::Int(a), Unpacked::Number(b)) => int_eq_float(a, b),
+            (Unpacked::Number(a), Unpacked::Int(b)) => int_eq_float(b, a),
+            (Unpacked::Number(a), Unpacked::Number(b)) => a == b,
+            (Unpacked::Obj(a_obj), Unpacked::Obj(b_obj)) => unsafe {
+                match (&(*a_obj).kind, &(*b_obj).kind) {
+                    (ObjKind::String, ObjKind::String) => {
+                        let a = a_obj as *mut ObjString;
+                        let b = b_obj as *mut ObjString;
+                        (*a).value == (*b).value
+                    }
+                    (ObjKind::Range, ObjKind::Range) => {
+                        let a = &*(a_obj as *mut ObjRange);
+                        let b = &*(b_obj as *mut ObjRange);
+                        a.start == b.start
+                            && a.end == b.end
+                            && a.inclusive == b.inclusive
+                            && a.step == b.step
+                    }
+                    _ => ptr::eq(a_obj, b_obj),
+                }
             },
-            _ => unsafe {
-                if discriminant(self) != discriminant(other) {
-                    false
+            _ => false,
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self.unpack(), other.unpack()) {
+            (Unpacked::Int(a), Unpacked::Int(b)) => Some(a.cmp(&b)),
+            (Unpacked::Int(a), Unpacked::Number(b)) => int_cmp_float(a, b),
+            (Unpacked::Number(a), Unpacked::Int(b)) => int_cmp_float(b, a).map(Ordering::reverse),
+            (Unpacked::Number(a), Unpacked::Number(b)) => a.partial_cmp(&b),
+            (Unpacked::Obj(a), Unpacked::Obj(b)) => unsafe {
+                if (*a).kind == ObjKind::String && (*b).kind == ObjKind::String {
+                    let a = a as *mut ObjString;
+                    let b = b as *mut ObjString;
+                    (*a).value.partial_cmp(&(*b).value)
                 } else {
-                    let Value::Obj(a_obj) = self else {
-                        unreachable!()
-                    };
-                    let Value::Obj(b_obj) = other else {
-                        unreachable!()
-                    };
-
-                    match (**a_obj).kind {
-                        ObjKind::String => match (**b_obj).kind {
-                            ObjKind::String => {
-                                let a = *a_obj as *mut ObjString;
-                                let b = *b_obj as *mut ObjString;
-                                (*a).value == (*b).value
-                            }
-                        },
-                    }
+                    None
                 }
             },
+            _ => None,
         }
     }
 }
@@ -103,3 +406,74 @@ impl ValueArray {
         self.values.push(value);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rvm::RVM;
+
+    #[test]
+    fn values_round_trip() {
+        let ints = [
+            0,
+            -1,
+            (1 << 48) - 1,
+            1 << 48,
+            -(1 << 48),
+            -(1 << 48) - 1,
+            i64::MAX,
+            i64::MIN,
+        ];
+        let mut vm = RVM::new();
+        for n in ints {
+            assert!(matches!(vm.int(n).unpack(), Unpacked::Int(m) if m == n));
+        }
+        for n in [
+            0.0,
+            -0.0,
+            1.5,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::MIN_POSITIVE,
+        ] {
+            let Unpacked::Number(m) = Value::number(n).unpack() else {
+                panic!("{} did not unpack as a number", n);
+            };
+            assert_eq!(m.to_bits(), n.to_bits());
+        }
+
+        let tagged_nan = f64::from_bits(0xfffc_0000_0000_0001);
+        assert!(Value::number(tagged_nan)
+            .as_number()
+            .is_some_and(f64::is_nan));
+        assert!(Value::nil().is_nil());
+        assert_eq!(Value::boolean(true).as_bool(), Some(true));
+        assert_eq!(Value::boolean(false).as_bool(), Some(false));
+        assert_eq!(Value::default().as_int(), None);
+    }
+
+    #[test]
+    fn collections_quote_strings() {
+        let mut vm = RVM::new();
+        let printed = |vm: &mut RVM, source| vm.interpret_source(source).ok().unwrap().to_string();
+        assert_eq!(printed(&mut vm, "\"s\""), "s");
+        assert_eq!(
+            printed(&mut vm, "[\"1\", 1, [\"a\" + \"b\"], nil]"),
+            "[\"1\", 1, [\"ab\"], nil]"
+        );
+        assert_eq!(
+            printed(&mut vm, "{\"1\": \"one\", 1: [\"a\"]}"),
+            "{\"1\": \"one\", 1: [\"a\"]}"
+        );
+    }
+
+    #[test]
+    fn wide_ints_live_on_the_vm_heap() {
+        let mut vm = RVM::new();
+        for n in [-1, 1 << 48, i64::MIN] {
+            vm.int(n);
+        }
+        let boxed = if cfg!(feature = "nan-boxing") { 2 } else { 0 };
+        assert!(vm.heap_stats().contains(&("int", boxed)));
+    }
+}
@@ -0,0 +1,242 @@
+use crate::chunk::{Chunk, OpCode};
+use crate::object::ObjKind;
+
+/// Deepest point the stack reaches while running a chunk.
+pub struct StackUsage {
+    pub depth: usize,
+    /// Offset of the first instruction that reaches `depth`
+    pub offset: usize,
+}
+
+/// Checks everything `RVM::run` takes on trust: every opcode is known, its
+/// operands are in bounds, constant indices point into the pool, the stack
+/// never underflows, and the code ends with its only `Return`. Returns how much stack the chunk needs, so the
+/// VM can size its stack before running.
+///
+/// There are no jump instructions yet, so code runs straight through and a
+/// single pass tracks the exact stack depth at every instruction.
+pub fn verify(chunk: &Chunk) -> Result<StackUsage, String> {
+    let code = &chunk.code;
+    let constants = &chunk.constants.values;
+
+    if !code.is_empty() && chunk.lines.first().is_none_or(|l| l.offset != 0) {
+        return Err("Line table does not start at offset 0".to_string());
+    }
+    if !chunk
+        .lines
+        .windows(2)
+        .all(|w| w[0].offset < w[1].offset && w[1].offset < code.len())
+    {
+        return Err("Line table offsets are out of order or out of range".to_string());
+    }
+
+    let mut depth = 0;
+    let mut usage = StackUsage {
+        depth: 0,
+        offset: 0,
+    };
+    for instruction in chunk.instructions() {
+        let offset = instruction.offset;
+        let Some(op) = instruction.opcode else {
+            return Err(format!(
+                "Invalid opcode {} at offset {}",
+                code[offset], offset
+            ));
+        };
+        if instruction.operands.len() < op.operand_bytes() {
+            return Err(format!("Truncated operands at offset {}", offset));
+        }
+
+        if let Some(idx) = instruction.constant() {
+            let Some(value) = constants.get(idx) else {
+                return Err(format!(
+                    "Constant index {} out of range at offset {}",
+                    idx, offset
+                ));
+            };
+            let is_string = unsafe { value.as_obj_of(ObjKind::String).is_some() };
+            if matches!(op, OpCode::Invoke) && !is_string {
+                return Err(format!("Method name is not a string at offset {}", offset));
+            }
+        }
+
+        let (pops, pushes) = stack_effect(op, instruction.operands);
+        if depth < pops {
+            return Err(format!("Stack underflow at offset {}", offset));
+        }
+        depth = depth - pops + pushes;
+        if depth > usage.depth {
+            usage = StackUsage { depth, offset };
+        }
+
+        if matches!(op, OpCode::Return) {
+            if instruction.next_offset() != code.len() {
+                return Err(format!("Code after Return at offset {}", offset));
+            }
+            return Ok(usage);
+        }
+    }
+
+    Err("Code does not end with Return".to_string())
+}
+
+/// How many values `op` pops from the stack and how many it pushes.
+fn stack_effect(op: OpCode, operands: &[u8]) -> (usize, usize) {
+    match op {
+        OpCode::Constant | OpCode::ConstantLong | OpCode::Nil | OpCode::True | OpCode::False => {
+            (0, 1)
+        }
+        OpCode::Equal
+        | OpCode::NotEqual
+        | OpCode::Greater
+        | OpCode::GreaterEqual
+        | OpCode::Less
+        | OpCode::LessEqual
+        | OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::IntDivide
+        | OpCode::Modulo
+        | OpCode::Power
+        | OpCode::BitAnd
+        | OpCode::BitOr
+        | OpCode::BitXor
+        | OpCode::ShiftLeft
+        | OpCode::ShiftRight
+        | OpCode::Range
+        | OpCode::RangeInclusive
+        | OpCode::IndexGet => (2, 1),
+        OpCode::Not | OpCode::Negate | OpCode::BitNot | OpCode::AddConstant => (1, 1),
+        OpCode::BuildList => (operands[0] as usize, 1),
+        OpCode::BuildMap => (operands[0] as usize * 2, 1),
+        OpCode::IndexSet => (3, 1),
+        OpCode::Invoke => (operands[1] as usize + 1, 1),
+        OpCode::Return => (1, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::LineStart;
+    use crate::value::Value;
+
+    fn chunk(code: &[u8], constants: &[Value]) -> Chunk {
+        let mut chunk = Chunk::new();
+        for &byte in code {
+            chunk.write(byte, 1);
+        }
+        for value in constants {
+            chunk.add_constant(value.clone());
+        }
+        chunk
+    }
+
+    fn error(chunk: &Chunk) -> String {
+        verify(chunk).err().unwrap()
+    }
+
+    #[test]
+    fn measures_stack_depth() {
+        let code = [
+            OpCode::Nil as u8,
+            OpCode::Constant as u8,
+            0,
+            OpCode::Constant as u8,
+            0,
+            OpCode::BuildList as u8,
+            3,
+            OpCode::Return as u8,
+        ];
+        let usage = verify(&chunk(&code, &[Value::int(1)])).unwrap();
+        assert_eq!((usage.depth, usage.offset), (3, 3));
+    }
+
+    #[test]
+    fn rejects_malformed_instructions() {
+        assert_eq!(
+            error(&chunk(&[OpCode::Nil as u8, 0xff], &[])),
+            "Invalid opcode 255 at offset 1"
+        );
+        assert_eq!(
+            error(&chunk(&[OpCode::Nil as u8, OpCode::Invoke as u8, 0], &[])),
+            "Truncated operands at offset 1"
+        );
+        assert_eq!(
+            error(&chunk(
+                &[OpCode::Constant as u8, 1, OpCode::Return as u8],
+                &[Value::nil()]
+            )),
+            "Constant index 1 out of range at offset 0"
+        );
+        assert_eq!(
+            error(&chunk(
+                &[
+                    OpCode::Nil as u8,
+                    OpCode::Invoke as u8,
+                    0,
+                    0,
+                    OpCode::Return as u8
+                ],
+                &[Value::int(1)]
+            )),
+            "Method name is not a string at offset 1"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_stack_use() {
+        assert_eq!(
+            error(&chunk(&[OpCode::Nil as u8, OpCode::Add as u8], &[])),
+            "Stack underflow at offset 1"
+        );
+        assert_eq!(
+            error(&chunk(&[OpCode::Return as u8], &[])),
+            "Stack underflow at offset 0"
+        );
+        assert_eq!(
+            error(&chunk(&[OpCode::Nil as u8, OpCode::Not as u8], &[])),
+            "Code does not end with Return"
+        );
+    }
+
+    #[test]
+    fn rejects_code_after_return() {
+        assert_eq!(
+            error(&chunk(
+                &[OpCode::Nil as u8, OpCode::Return as u8, 0xff],
+                &[]
+            )),
+            "Code after Return at offset 1"
+        );
+        assert_eq!(
+            error(&chunk(
+                &[
+                    OpCode::Nil as u8,
+                    OpCode::Return as u8,
+                    OpCode::Nil as u8,
+                    OpCode::Return as u8
+                ],
+                &[]
+            )),
+            "Code after Return at offset 1"
+        );
+    }
+
+    #[test]
+    fn rejects_a_bad_line_table() {
+        let mut bad = chunk(&[OpCode::Nil as u8, OpCode::Return as u8], &[]);
+        bad.lines = vec![LineStart { offset: 1, line: 1 }];
+        assert_eq!(error(&bad), "Line table does not start at offset 0");
+
+        bad.lines = vec![
+            LineStart { offset: 0, line: 1 },
+            LineStart { offset: 2, line: 2 },
+        ];
+        assert_eq!(
+            error(&bad),
+            "Line table offsets are out of order or out of range"
+        );
+    }
+}